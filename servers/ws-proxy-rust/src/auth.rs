@@ -0,0 +1,162 @@
+use tokio_tungstenite::tungstenite::handshake::server::Request;
+
+/// Close code sent to sessions that fail to authenticate.
+pub const CLOSE_UNAUTHORIZED: u16 = 4001;
+
+/// How long a session that did not authenticate during the handshake has to
+/// send its `auth` message.
+pub const AUTH_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Random 256-bit token, hex encoded.
+pub fn generate_token() -> Result<String, String> {
+    let mut bytes = [0u8; 32];
+    getrandom::getrandom(&mut bytes).map_err(|e| format!("cannot generate auth token: {e}"))?;
+    Ok(bytes.iter().map(|b| format!("{b:02x}")).collect())
+}
+
+/// Token presented during the WebSocket handshake, either as `?token=` in
+/// the URL (percent-encoded, as browsers send it) or as an
+/// `Authorization: Bearer` header.
+pub fn request_token(req: &Request) -> Option<String> {
+    let from_query = req.uri().query().and_then(|query| {
+        url::form_urlencoded::parse(query.as_bytes())
+            .find(|(name, _)| name == "token")
+            .map(|(_, value)| value.into_owned())
+    });
+    from_query.or_else(|| {
+        req.headers()
+            .get("authorization")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .map(|v| v.trim().to_string())
+    })
+}
+
+/// Constant-time comparison so the token cannot be guessed byte by byte.
+pub fn token_matches(presented: &str, expected: &str) -> bool {
+    let (a, b) = (presented.as_bytes(), expected.as_bytes());
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Whether a browser `Origin` is on the allowlist. Entries match exactly
+/// (ignoring case) or as `*` globs. A trailing `:*` stands for any port,
+/// including none, so `http://localhost:*` admits `http://localhost` but not
+/// `http://localhost:80.evil.example`.
+pub fn origin_allowed(origin: &str, allowed: &[String]) -> bool {
+    let origin = origin.to_ascii_lowercase();
+    allowed.iter().any(|pattern| {
+        let pattern = pattern.to_ascii_lowercase();
+        match pattern.strip_suffix(":*") {
+            Some(base) => match origin.strip_prefix(base) {
+                Some("") => true,
+                Some(rest) => rest.strip_prefix(':').is_some_and(|port| {
+                    !port.is_empty() && port.bytes().all(|b| b.is_ascii_digit())
+                }),
+                None => false,
+            },
+            None => wildcard_match(&pattern, &origin),
+        }
+    })
+}
+
+/// Glob match where `*` stands for any run of characters.
+pub fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let Some((first, rest)) = pattern.split_once('*') else {
+        return pattern == text;
+    };
+    let Some(mut remaining) = text.strip_prefix(first) else {
+        return false;
+    };
+    let parts: Vec<&str> = rest.split('*').collect();
+    let (last, middle) = parts.split_last().unwrap();
+    for part in middle {
+        match remaining.find(part) {
+            Some(pos) => remaining = &remaining[pos + part.len()..],
+            None => return false,
+        }
+    }
+    remaining.len() >= last.len() && remaining.ends_with(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handshake(uri: &str, authorization: Option<&str>) -> Request {
+        let mut builder = Request::builder().uri(uri);
+        if let Some(value) = authorization {
+            builder = builder.header("authorization", value);
+        }
+        builder.body(()).unwrap()
+    }
+
+    #[test]
+    fn query_token_is_percent_decoded() {
+        let req = handshake("/?session=1&token=a%2Bb%20c", None);
+        assert_eq!(request_token(&req).as_deref(), Some("a+b c"));
+    }
+
+    #[test]
+    fn query_token_wins_over_header() {
+        let req = handshake("/?token=query", Some("Bearer header"));
+        assert_eq!(request_token(&req).as_deref(), Some("query"));
+    }
+
+    #[test]
+    fn bearer_header_is_read() {
+        let req = handshake("/", Some("Bearer  secret "));
+        assert_eq!(request_token(&req).as_deref(), Some("secret"));
+        let req = handshake("/", Some("Basic c2VjcmV0"));
+        assert_eq!(request_token(&req), None);
+    }
+
+    #[test]
+    fn token_must_match_exactly() {
+        assert!(token_matches("abcd", "abcd"));
+        assert!(!token_matches("abce", "abcd"));
+        assert!(!token_matches("abc", "abcd"));
+        assert!(!token_matches("abcde", "abcd"));
+        assert!(!token_matches("", "abcd"));
+    }
+
+    fn allowed(patterns: &[&str]) -> Vec<String> {
+        patterns.iter().map(|p| p.to_string()).collect()
+    }
+
+    #[test]
+    fn any_port_pattern_needs_a_real_port() {
+        let allowed = allowed(&["http://localhost:*"]);
+        assert!(origin_allowed("http://localhost", &allowed));
+        assert!(origin_allowed("http://localhost:5173", &allowed));
+        assert!(!origin_allowed(
+            "http://localhost:80.evil.example",
+            &allowed
+        ));
+        assert!(!origin_allowed("http://localhost.evil.example", &allowed));
+        assert!(!origin_allowed("http://localhost:", &allowed));
+        assert!(!origin_allowed("https://localhost", &allowed));
+    }
+
+    #[test]
+    fn origins_ignore_case() {
+        let allowed = allowed(&["HTTP://LocalHost:*", "https://App.Example.com"]);
+        assert!(origin_allowed("http://LOCALHOST:8080", &allowed));
+        assert!(origin_allowed("HTTPS://app.example.COM", &allowed));
+    }
+
+    #[test]
+    fn glob_patterns() {
+        let allowed = allowed(&["https://*.example.com"]);
+        assert!(origin_allowed("https://app.example.com", &allowed));
+        assert!(origin_allowed("https://a.b.example.com", &allowed));
+        assert!(!origin_allowed("https://example.com", &allowed));
+        assert!(!origin_allowed("http://app.example.com", &allowed));
+        assert!(!origin_allowed("https://app.example.com.evil", &allowed));
+        assert!(wildcard_match("a*b*c", "aXbYc"));
+        assert!(!wildcard_match("a*bc", "abc."));
+        assert!(wildcard_match("*", ""));
+    }
+}
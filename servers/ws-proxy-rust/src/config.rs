@@ -0,0 +1,548 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+
+use clap::{Parser, ValueEnum};
+use ipnet::IpNet;
+use serde::Deserialize;
+
+/// Command-line interface. Every flag can also be set through its
+/// `MHNOS_PROXY_*` environment variable; both override the config file.
+#[derive(Debug, Parser)]
+#[command(
+    name = "mhnos-ws-proxy",
+    version,
+    about = "WebSocket network proxy for MHNOS"
+)]
+pub struct Cli {
+    /// TOML config file
+    #[arg(short, long, env = "MHNOS_PROXY_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Address to listen on; repeat (or comma-separate) for several
+    #[arg(short, long, env = "MHNOS_PROXY_BIND", value_delimiter = ',')]
+    pub bind: Vec<String>,
+
+    /// Port used for bind addresses that do not carry their own
+    #[arg(short, long, env = "MHNOS_PROXY_PORT")]
+    pub port: Option<u16>,
+
+    /// Serve wss:// instead of ws://, with a self-signed certificate unless
+    /// one is given
+    #[arg(long, env = "MHNOS_PROXY_TLS")]
+    pub tls: bool,
+
+    /// PEM certificate chain for wss://; implies --tls
+    #[arg(long, env = "MHNOS_PROXY_TLS_CERT", requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM private key for --tls-cert
+    #[arg(long, env = "MHNOS_PROXY_TLS_KEY", requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+
+    /// Directory where named cookie profiles are saved
+    #[arg(long, env = "MHNOS_PROXY_COOKIE_DIR")]
+    pub cookie_dir: Option<PathBuf>,
+
+    /// Log filter, e.g. `info` or `mhnos_ws_proxy=debug`
+    #[arg(long, env = "MHNOS_PROXY_LOG_LEVEL")]
+    pub log_level: Option<String>,
+
+    /// Maximum open TCP streams per session
+    #[arg(long, env = "MHNOS_PROXY_MAX_STREAMS")]
+    pub max_streams: Option<usize>,
+
+    /// Maximum in-flight fetches per session
+    #[arg(long, env = "MHNOS_PROXY_MAX_FETCHES")]
+    pub max_fetches: Option<usize>,
+
+    /// Maximum size of a single WebSocket message in bytes
+    #[arg(long, env = "MHNOS_PROXY_MAX_MESSAGE_SIZE")]
+    pub max_message_size: Option<usize>,
+
+    /// Reject `fetch` requests
+    #[arg(long, env = "MHNOS_PROXY_NO_FETCH")]
+    pub no_fetch: bool,
+
+    /// Reject `tcp_open` requests
+    #[arg(long, env = "MHNOS_PROXY_NO_TCP")]
+    pub no_tcp: bool,
+
+    /// Reject `tcp_listen` requests
+    #[arg(long, env = "MHNOS_PROXY_NO_TCP_LISTEN")]
+    pub no_tcp_listen: bool,
+
+    /// Reject `udp_bind` requests
+    #[arg(long, env = "MHNOS_PROXY_NO_UDP")]
+    pub no_udp: bool,
+
+    /// Reject `dns_lookup` and `dns_resolve` requests
+    #[arg(long, env = "MHNOS_PROXY_NO_DNS")]
+    pub no_dns: bool,
+
+    /// Never switch sessions to binary stream frames
+    #[arg(long, env = "MHNOS_PROXY_NO_BINARY_FRAMES")]
+    pub no_binary_frames: bool,
+
+    /// Refuse resumable sessions
+    #[arg(long, env = "MHNOS_PROXY_NO_RESUME")]
+    pub no_resume: bool,
+
+    /// Shared secret clients must present; generated at startup if unset
+    #[arg(long, env = "MHNOS_PROXY_TOKEN", hide_env_values = true)]
+    pub token: Option<String>,
+
+    /// Accept sessions without a token
+    #[arg(long, env = "MHNOS_PROXY_NO_AUTH")]
+    pub no_auth: bool,
+
+    /// Browser origin allowed to connect; repeat (or comma-separate) for several
+    #[arg(long, env = "MHNOS_PROXY_ALLOWED_ORIGINS", value_delimiter = ',')]
+    pub allow_origin: Vec<String>,
+
+    /// Do not trust the operating system's certificate store for outbound TLS
+    #[arg(long, env = "MHNOS_PROXY_NO_SYSTEM_ROOTS")]
+    pub no_system_roots: bool,
+
+    /// Egress action for destinations no rule matches
+    #[arg(long, env = "MHNOS_PROXY_EGRESS_DEFAULT", value_enum)]
+    pub egress_default: Option<Action>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub bind: Vec<String>,
+    pub port: u16,
+    pub log_level: String,
+    pub limits: Limits,
+    pub features: Features,
+    pub auth: Auth,
+    pub egress: Egress,
+    pub inbound: Inbound,
+    pub tls: Tls,
+    pub server_tls: ServerTls,
+    pub cookies: Cookies,
+}
+
+/// Per-session limits.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    pub max_streams: usize,
+    pub max_fetches: usize,
+    pub max_listeners: usize,
+    pub max_udp_sockets: usize,
+    pub max_message_size: usize,
+    pub max_frame_size: usize,
+    pub tcp_read_chunk: usize,
+    pub upload_queue_chunks: usize,
+    /// Bytes of replies, stream data and response bodies that may wait for a
+    /// slow client before readers stop pulling from their sockets and the
+    /// session stops reading requests.
+    pub outbound_queue_bytes: usize,
+    /// How long a resumable session outlives its WebSocket.
+    pub resume_timeout_secs: u64,
+    /// Bytes of already sent frames a resumable session keeps for replay.
+    pub resume_buffer_bytes: usize,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Features {
+    pub fetch: bool,
+    pub tcp: bool,
+    pub tcp_listen: bool,
+    pub udp: bool,
+    pub dns: bool,
+    pub binary_frames: bool,
+    pub resume: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Auth {
+    pub enabled: bool,
+    /// Browsers pass it as `?token=`, percent-encoded if it is not URL-safe.
+    pub token: Option<String>,
+    /// `Origin` values accepted on the WebSocket upgrade; see `auth::origin_allowed`.
+    pub allowed_origins: Vec<String>,
+    /// Admit upgrades without an `Origin` header, i.e. non-browser clients.
+    pub allow_missing_origin: bool,
+}
+
+/// Host-side listening sockets opened with `tcp_listen`, and `udp_bind`
+/// sockets given a host or a fixed port. Other `udp_bind` sockets are clients:
+/// they bind every interface but only hear from peers they have sent to.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Inbound {
+    /// Addresses `tcp_listen` and server `udp_bind` may bind, compared as written in the request.
+    pub allowed_hosts: Vec<String>,
+    /// Used when `tcp_listen` or a fixed-port `udp_bind` names no host.
+    pub default_host: String,
+}
+
+/// Certificates for outbound TLS from `tcp_open` and `fetch`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Tls {
+    /// PEM files of extra roots, trusted alongside the built-in Mozilla set.
+    pub ca_files: Vec<PathBuf>,
+    /// Also trust the operating system's certificate store, as fetch did
+    /// when it used the platform's TLS.
+    pub system_roots: bool,
+    /// Client certificates requests can name with `clientCert`.
+    pub client_certs: HashMap<String, ClientCertFiles>,
+}
+
+/// TLS on the proxy's own listeners, so clients connect with `wss://`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerTls {
+    pub enabled: bool,
+    /// PEM certificate chain and key. Without them a self-signed certificate
+    /// is generated at every start.
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    /// Names and addresses the self-signed certificate is valid for.
+    pub self_signed_names: Vec<String>,
+}
+
+/// Cookie jars for `fetch`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Cookies {
+    /// Where named profiles are saved as `<name>.json`. Without it profiles
+    /// last until the proxy exits.
+    pub dir: Option<PathBuf>,
+    /// Public suffix list (`public_suffix_list.dat`) that keeps sites from
+    /// setting cookies for all of `com` or `co.uk`. Found in the usual system
+    /// places if unset; without one cookies stay with the host that set them.
+    pub public_suffix_list: Option<PathBuf>,
+}
+
+/// PEM files of a client certificate chain and its private key.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClientCertFiles {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// Outbound policy for `tcp_open` and `fetch`, evaluated by `policy::decide`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Egress {
+    pub default: Action,
+    /// Checked in order; the first matching rule decides.
+    pub rules: Vec<EgressRule>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Allow,
+    Deny,
+}
+
+/// A rule matches when every non-empty criterion does.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EgressRule {
+    pub action: Action,
+    /// `*` globs over the requested host name.
+    #[serde(default)]
+    pub hosts: Vec<String>,
+    /// Matched against every resolved address.
+    #[serde(default)]
+    pub cidrs: Vec<IpNet>,
+    #[serde(default)]
+    pub ports: Vec<PortRange>,
+    /// `http`/`https` for fetches, `tcp`/`tls` for `tcp_open`, `udp` for
+    /// `udp_send`.
+    #[serde(default)]
+    pub schemes: Vec<String>,
+}
+
+/// A port (`443`) or inclusive range (`"8000-8999"`).
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(try_from = "PortSpec")]
+pub struct PortRange {
+    pub first: u16,
+    pub last: u16,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PortSpec {
+    Port(u16),
+    Range(String),
+}
+
+impl TryFrom<PortSpec> for PortRange {
+    type Error = String;
+
+    fn try_from(spec: PortSpec) -> Result<Self, String> {
+        let range = match spec {
+            PortSpec::Port(port) => {
+                return Ok(Self {
+                    first: port,
+                    last: port,
+                })
+            }
+            PortSpec::Range(range) => range,
+        };
+        let parse = |s: &str| {
+            s.trim()
+                .parse::<u16>()
+                .map_err(|e| format!("invalid port range {range}: {e}"))
+        };
+        let (first, last) = match range.split_once('-') {
+            Some((first, last)) => (parse(first)?, parse(last)?),
+            None => (parse(&range)?, parse(&range)?),
+        };
+        if first > last {
+            return Err(format!("invalid port range {range}"));
+        }
+        Ok(Self { first, last })
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bind: vec!["127.0.0.1".to_string()],
+            port: 5772,
+            log_level: "info".to_string(),
+            limits: Limits::default(),
+            features: Features::default(),
+            auth: Auth::default(),
+            egress: Egress::default(),
+            inbound: Inbound::default(),
+            tls: Tls::default(),
+            server_tls: ServerTls::default(),
+            cookies: Cookies::default(),
+        }
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_streams: 256,
+            max_fetches: 64,
+            max_listeners: 16,
+            max_udp_sockets: 64,
+            max_message_size: 64 << 20,
+            max_frame_size: 16 << 20,
+            tcp_read_chunk: 16 * 1024,
+            upload_queue_chunks: 4,
+            outbound_queue_bytes: 4 << 20,
+            resume_timeout_secs: 30,
+            resume_buffer_bytes: 4 << 20,
+        }
+    }
+}
+
+impl Default for Features {
+    fn default() -> Self {
+        Self {
+            fetch: true,
+            tcp: true,
+            tcp_listen: true,
+            udp: true,
+            dns: true,
+            binary_frames: true,
+            resume: true,
+        }
+    }
+}
+
+impl Default for Auth {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            token: None,
+            allowed_origins: [
+                "http://localhost:*",
+                "http://127.0.0.1:*",
+                "http://[::1]:*",
+                "https://localhost:*",
+                "https://127.0.0.1:*",
+                "https://[::1]:*",
+            ]
+            .map(String::from)
+            .to_vec(),
+            allow_missing_origin: true,
+        }
+    }
+}
+
+impl Default for Inbound {
+    fn default() -> Self {
+        Self {
+            allowed_hosts: ["127.0.0.1", "::1", "localhost"].map(String::from).to_vec(),
+            default_host: "127.0.0.1".to_string(),
+        }
+    }
+}
+
+impl Default for Tls {
+    fn default() -> Self {
+        Self {
+            ca_files: Vec::new(),
+            system_roots: true,
+            client_certs: HashMap::new(),
+        }
+    }
+}
+
+impl Default for ServerTls {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            cert: None,
+            key: None,
+            self_signed_names: ["localhost", "127.0.0.1", "::1"].map(String::from).to_vec(),
+        }
+    }
+}
+
+impl Default for Egress {
+    fn default() -> Self {
+        // Cloud metadata endpoints live in the link-local ranges.
+        let metadata = EgressRule {
+            action: Action::Deny,
+            hosts: Vec::new(),
+            cidrs: ["169.254.0.0/16", "fe80::/10", "fd00:ec2::254/128"]
+                .map(|net| net.parse().unwrap())
+                .to_vec(),
+            ports: Vec::new(),
+            schemes: Vec::new(),
+        };
+        Self {
+            default: Action::Allow,
+            rules: vec![metadata],
+        }
+    }
+}
+
+impl Config {
+    /// Defaults, then the config file, then environment and flags.
+    pub fn load(cli: Cli) -> Result<Self, String> {
+        let mut config = match &cli.config {
+            Some(path) => Self::from_file(path)?,
+            None => Self::default(),
+        };
+
+        if !cli.bind.is_empty() {
+            config.bind = cli.bind;
+        }
+        if let Some(port) = cli.port {
+            config.port = port;
+        }
+        if cli.tls {
+            config.server_tls.enabled = true;
+        }
+        if let (Some(cert), Some(key)) = (cli.tls_cert, cli.tls_key) {
+            config.server_tls.enabled = true;
+            config.server_tls.cert = Some(cert);
+            config.server_tls.key = Some(key);
+        }
+        if let Some(dir) = cli.cookie_dir {
+            config.cookies.dir = Some(dir);
+        }
+        if let Some(level) = cli.log_level {
+            config.log_level = level;
+        }
+        if let Some(n) = cli.max_streams {
+            config.limits.max_streams = n;
+        }
+        if let Some(n) = cli.max_fetches {
+            config.limits.max_fetches = n;
+        }
+        if let Some(n) = cli.max_message_size {
+            config.limits.max_message_size = n;
+        }
+        if cli.no_fetch {
+            config.features.fetch = false;
+        }
+        if cli.no_tcp {
+            config.features.tcp = false;
+        }
+        if cli.no_tcp_listen {
+            config.features.tcp_listen = false;
+        }
+        if cli.no_udp {
+            config.features.udp = false;
+        }
+        if cli.no_dns {
+            config.features.dns = false;
+        }
+        if cli.no_binary_frames {
+            config.features.binary_frames = false;
+        }
+        if cli.no_resume {
+            config.features.resume = false;
+        }
+        if let Some(token) = cli.token {
+            config.auth.token = Some(token);
+        }
+        if cli.no_auth {
+            config.auth.enabled = false;
+        }
+        if !cli.allow_origin.is_empty() {
+            config.auth.allowed_origins = cli.allow_origin;
+        }
+        if cli.no_system_roots {
+            config.tls.system_roots = false;
+        }
+        if let Some(action) = cli.egress_default {
+            config.egress.default = action;
+        }
+
+        if config.limits.tcp_read_chunk == 0
+            || config.limits.upload_queue_chunks == 0
+            || config.limits.outbound_queue_bytes == 0
+        {
+            return Err(
+                "tcp_read_chunk, upload_queue_chunks and outbound_queue_bytes must be positive"
+                    .to_string(),
+            );
+        }
+        if config.server_tls.cert.is_some() != config.server_tls.key.is_some() {
+            return Err("server_tls cert and key must be given together".to_string());
+        }
+        if config.auth.token.as_deref() == Some("") {
+            return Err("auth token must not be empty".to_string());
+        }
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("cannot read config {}: {e}", path.display()))?;
+        toml::from_str(&text).map_err(|e| format!("invalid config {}: {e}", path.display()))
+    }
+
+    /// Resolves `bind` entries; bare IPs take `port`, `ip:port` entries keep theirs.
+    pub fn listen_addrs(&self) -> Result<Vec<SocketAddr>, String> {
+        if self.bind.is_empty() {
+            return Err("no bind address configured".to_string());
+        }
+        self.bind
+            .iter()
+            .map(|entry| {
+                if let Ok(addr) = entry.parse::<SocketAddr>() {
+                    return Ok(addr);
+                }
+                entry
+                    .trim_matches(|c| c == '[' || c == ']')
+                    .parse::<IpAddr>()
+                    .map(|ip| SocketAddr::new(ip, self.port))
+                    .map_err(|e| format!("invalid bind address {entry}: {e}"))
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,357 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use cookie_store::{CookieStore, RawCookie};
+use publicsuffix::{List, Psl};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+use crate::config::Cookies;
+
+/// Which jar a request uses: `true` for the session's own, or the name of a
+/// profile shared by every session.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum JarName {
+    Session(bool),
+    Profile(String),
+}
+
+/// Named jars, kept for the life of the proxy and, when `dir` is set, saved
+/// there as `<name>.json` so logins survive restarts.
+struct Profiles {
+    dir: Option<PathBuf>,
+    jars: Mutex<HashMap<String, Arc<Mutex<CookieStore>>>>,
+    /// Checked by `insert` for every jar, session ones included.
+    public_suffixes: Option<List>,
+    /// Serialized profiles on their way to `write_profiles`.
+    writer: Option<mpsc::UnboundedSender<(PathBuf, Vec<u8>)>>,
+}
+
+/// Where distributions install the public suffix list.
+const SYSTEM_SUFFIX_LISTS: &[&str] = &[
+    "/usr/share/publicsuffix/public_suffix_list.dat",
+    "/usr/local/share/publicsuffix/public_suffix_list.dat",
+    "/opt/homebrew/share/publicsuffix/public_suffix_list.dat",
+];
+
+static PROFILES: OnceLock<Profiles> = OnceLock::new();
+
+/// Call once at startup.
+pub fn init(cookies: &Cookies) -> Result<(), String> {
+    let mut writer = None;
+    if let Some(dir) = &cookies.dir {
+        create_private_dir(dir)
+            .map_err(|e| format!("cannot create cookie dir {}: {e}", dir.display()))?;
+        let (tx, rx) = mpsc::unbounded_channel();
+        std::thread::Builder::new()
+            .name("cookie-writer".to_string())
+            .spawn(move || write_profiles(rx))
+            .map_err(|e| format!("cannot start cookie writer: {e}"))?;
+        writer = Some(tx);
+    }
+    let path = cookies.public_suffix_list.clone().or_else(|| {
+        SYSTEM_SUFFIX_LISTS
+            .iter()
+            .map(PathBuf::from)
+            .find(|path| path.exists())
+    });
+    let public_suffixes = match path {
+        Some(path) => {
+            let text =
+                std::fs::read(&path).map_err(|e| format!("cannot read {}: {e}", path.display()))?;
+            let list = List::from_bytes(&text)
+                .map_err(|e| format!("invalid public suffix list {}: {e}", path.display()))?;
+            Some(list)
+        }
+        None => {
+            log::warn!("No public suffix list found; cookies are kept to the host that set them");
+            None
+        }
+    };
+    let _ = PROFILES.set(Profiles {
+        dir: cookies.dir.clone(),
+        jars: Mutex::default(),
+        public_suffixes,
+        writer,
+    });
+    Ok(())
+}
+
+fn profiles() -> &'static Profiles {
+    PROFILES.get_or_init(|| Profiles {
+        dir: None,
+        jars: Mutex::default(),
+        public_suffixes: None,
+        writer: None,
+    })
+}
+
+/// Stores a `Set-Cookie` value from `url`. A Domain attribute naming a public
+/// suffix such as `com` or `co.uk` would share the cookie with every site
+/// under it: the cookie is refused, or made host-only when the suffix is
+/// `url`'s own host, as RFC 6265 has it. Without a suffix list every Domain
+/// attribute is dropped.
+fn insert(store: &mut CookieStore, cookie: &str, url: &Url) -> Result<(), String> {
+    let mut raw = RawCookie::parse(cookie.to_string()).map_err(|e| e.to_string())?;
+    if let Some(domain) = raw.domain() {
+        let domain = domain.trim_start_matches('.').to_ascii_lowercase();
+        let keep = match &profiles().public_suffixes {
+            Some(list) => {
+                let public = list
+                    .suffix(domain.as_bytes())
+                    .filter(|suffix| suffix.is_known())
+                    .is_some_and(|suffix| suffix == domain.as_str());
+                if public && url.host_str() != Some(domain.as_str()) {
+                    return Err(format!("domain {domain} is a public suffix"));
+                }
+                !public
+            }
+            None => false,
+        };
+        if !keep {
+            raw.unset_domain();
+        }
+    }
+    store
+        .insert_raw(&raw, url)
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+impl Profiles {
+    fn path(&self, name: &str) -> Option<PathBuf> {
+        self.dir
+            .as_ref()
+            .map(|dir| dir.join(format!("{name}.json")))
+    }
+
+    /// The profile's jar, read from disk on first use.
+    fn get(&self, name: &str) -> Result<Arc<Mutex<CookieStore>>, String> {
+        let valid = name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if name.is_empty() || !valid {
+            return Err(format!("invalid cookie profile: {name}"));
+        }
+        let mut jars = self.jars.lock().unwrap();
+        if let Some(store) = jars.get(name) {
+            return Ok(store.clone());
+        }
+        let store = match self.path(name) {
+            Some(path) if path.exists() => load(&path)?,
+            _ => CookieStore::default(),
+        };
+        let store = Arc::new(Mutex::new(store));
+        jars.insert(name.to_string(), store.clone());
+        Ok(store)
+    }
+
+    /// Queues the profile for `write_profiles`; only serializing happens here,
+    /// as this runs on the runtime with the jar locked.
+    fn save(&self, name: &str, store: &CookieStore) {
+        let (Some(path), Some(writer)) = (self.path(name), &self.writer) else {
+            return;
+        };
+        let mut json = Vec::new();
+        // Session cookies are kept too: they are what keeps most logins.
+        match cookie_store::serde::json::save_incl_expired_and_nonpersistent(store, &mut json) {
+            Ok(()) => {
+                let _ = writer.send((path, json));
+            }
+            Err(e) => log::warn!("Cannot save cookie profile {name}: {e}"),
+        }
+    }
+}
+
+/// Writes queued profiles to disk, on a thread of its own. A burst of saves
+/// is written once per file, with its latest contents.
+fn write_profiles(mut rx: mpsc::UnboundedReceiver<(PathBuf, Vec<u8>)>) {
+    while let Some((path, json)) = rx.blocking_recv() {
+        let mut pending = HashMap::from([(path, json)]);
+        while let Ok((path, json)) = rx.try_recv() {
+            pending.insert(path, json);
+        }
+        for (path, json) in pending {
+            let tmp = path.with_extension("json.tmp");
+            let written = write_private(&tmp, &json).and_then(|()| std::fs::rename(&tmp, &path));
+            if let Err(e) = written {
+                log::warn!("Cannot save cookie profile {}: {e}", path.display());
+            }
+        }
+    }
+}
+
+/// Profiles hold login sessions, so only the proxy's user may read them.
+fn create_private_dir(dir: &Path) -> std::io::Result<()> {
+    let mut builder = std::fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+    builder.create(dir)
+}
+
+/// Creates `path` afresh, as the mode only applies to new files.
+fn write_private(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let _ = std::fs::remove_file(path);
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    std::io::Write::write_all(&mut options.open(path)?, contents)
+}
+
+fn load(path: &Path) -> Result<CookieStore, String> {
+    let file =
+        std::fs::File::open(path).map_err(|e| format!("cannot read {}: {e}", path.display()))?;
+    cookie_store::serde::json::load_all(std::io::BufReader::new(file))
+        .map_err(|e| format!("invalid cookie file {}: {e}", path.display()))
+}
+
+/// A cookie as `cookie_list` reports it.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CookieInfo {
+    name: String,
+    value: String,
+    domain: Option<String>,
+    /// Sent only to `domain` itself, not its subdomains.
+    host_only: bool,
+    path: String,
+    /// Unix seconds; `None` for a session cookie.
+    expires: Option<i64>,
+    secure: bool,
+    http_only: bool,
+    same_site: Option<String>,
+}
+
+/// A session's jars: its own, which lives and dies with the session, and
+/// the shared profiles.
+#[derive(Clone, Default)]
+pub struct Jars {
+    session: Arc<Mutex<CookieStore>>,
+}
+
+impl Jars {
+    /// The jar `name` selects, or `None` for `false`.
+    pub fn select(&self, name: &JarName) -> Result<Option<Jar>, String> {
+        match name {
+            JarName::Session(false) => Ok(None),
+            JarName::Session(true) => Ok(Some(Jar {
+                store: self.session.clone(),
+                profile: None,
+            })),
+            JarName::Profile(name) => Ok(Some(Jar {
+                store: profiles().get(name)?,
+                profile: Some(name.clone()),
+            })),
+        }
+    }
+}
+
+pub struct Jar {
+    store: Arc<Mutex<CookieStore>>,
+    profile: Option<String>,
+}
+
+impl Jar {
+    /// The `Cookie` header value for a request to `url`, if any apply.
+    pub fn header(&self, url: &Url) -> Option<String> {
+        let store = self.store.lock().unwrap();
+        let pairs: Vec<String> = store
+            .get_request_values(url)
+            .map(|(name, value)| format!("{name}={value}"))
+            .collect();
+        (!pairs.is_empty()).then(|| pairs.join("; "))
+    }
+
+    /// Takes in a response's `Set-Cookie` values; cookies the store rejects
+    /// (say, for another domain) are skipped, as browsers do.
+    pub fn store<'a>(&self, url: &Url, set_cookies: impl Iterator<Item = &'a str>) {
+        let mut changed = false;
+        {
+            let mut store = self.store.lock().unwrap();
+            for cookie in set_cookies {
+                match insert(&mut store, cookie, url) {
+                    Ok(_) => changed = true,
+                    Err(e) => log::debug!("Ignored cookie from {url}: {e}"),
+                }
+            }
+        }
+        if changed {
+            self.save();
+        }
+    }
+
+    /// Adds a cookie as if `url` had sent it in `Set-Cookie`.
+    pub fn set(&self, url: &Url, cookie: &str) -> Result<(), String> {
+        insert(&mut self.store.lock().unwrap(), cookie, url)
+            .map_err(|e| format!("invalid cookie: {e}"))?;
+        self.save();
+        Ok(())
+    }
+
+    /// Unexpired cookies, only those a request to `url` would carry if given.
+    pub fn list(&self, url: Option<&Url>) -> Vec<CookieInfo> {
+        let store = self.store.lock().unwrap();
+        let cookies: Vec<_> = match url {
+            Some(url) => store.matches(url),
+            None => store.iter_unexpired().collect(),
+        };
+        cookies
+            .into_iter()
+            .map(|cookie| CookieInfo {
+                name: cookie.name().to_string(),
+                value: cookie.value().to_string(),
+                domain: cookie.domain.as_cow().map(|d| d.into_owned()),
+                host_only: matches!(cookie.domain, cookie_store::CookieDomain::HostOnly(_)),
+                path: cookie.path.to_string(),
+                expires: match &cookie.expires {
+                    cookie_store::CookieExpiration::AtUtc(at) => Some(at.unix_timestamp()),
+                    cookie_store::CookieExpiration::SessionEnd => None,
+                },
+                secure: cookie.secure().unwrap_or(false),
+                http_only: cookie.http_only().unwrap_or(false),
+                same_site: cookie.same_site().map(|s| s.to_string()),
+            })
+            .collect()
+    }
+
+    /// Drops every cookie, or those set for `domain` and its subdomains.
+    pub fn clear(&self, domain: Option<&str>) {
+        {
+            let mut store = self.store.lock().unwrap();
+            match domain {
+                None => store.clear(),
+                Some(domain) => {
+                    let domain = domain.trim_start_matches('.').to_ascii_lowercase();
+                    let doomed: Vec<(String, String, String)> = store
+                        .iter_any()
+                        .filter_map(|cookie| {
+                            let cookie_domain = cookie.domain.as_cow()?.into_owned();
+                            let hit = cookie_domain == domain
+                                || cookie_domain.ends_with(&format!(".{domain}"));
+                            hit.then(|| {
+                                let name = cookie.name().to_string();
+                                (cookie_domain, cookie.path.to_string(), name)
+                            })
+                        })
+                        .collect();
+                    for (domain, path, name) in doomed {
+                        store.remove(&domain, &path, &name);
+                    }
+                }
+            }
+        }
+        self.save();
+    }
+
+    fn save(&self) {
+        if let Some(name) = &self.profile {
+            // Queued with the jar locked, so saves reach the writer in order.
+            profiles().save(name, &self.store.lock().unwrap());
+        }
+    }
+}
@@ -0,0 +1,171 @@
+use std::net::IpAddr;
+use std::sync::OnceLock;
+
+use hickory_resolver::config::LookupIpStrategy;
+use hickory_resolver::error::{ResolveError, ResolveErrorKind};
+use hickory_resolver::proto::op::ResponseCode;
+use hickory_resolver::proto::rr::{RData, RecordType};
+use hickory_resolver::{Name, TokioAsyncResolver};
+use serde_json::{json, Value};
+
+/// A failed lookup, with the error code Node's `dns` module would report.
+pub struct DnsError {
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl DnsError {
+    fn new(code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+static RESOLVER: OnceLock<Result<TokioAsyncResolver, String>> = OnceLock::new();
+
+/// Process-wide resolver built from the host configuration (`resolv.conf` and
+/// the hosts file on Unix, the adapter settings on Windows).
+fn resolver() -> Result<&'static TokioAsyncResolver, DnsError> {
+    RESOLVER
+        .get_or_init(|| {
+            let (config, mut opts) = hickory_resolver::system_conf::read_system_conf()
+                .map_err(|e| format!("cannot read system DNS config: {e}"))?;
+            opts.ip_strategy = LookupIpStrategy::Ipv4AndIpv6;
+            Ok(TokioAsyncResolver::tokio(config, opts))
+        })
+        .as_ref()
+        .map_err(|e| DnsError::new("ECONNREFUSED", e.clone()))
+}
+
+/// `dns.lookup`: addresses of `hostname`, optionally of one family (4 or 6).
+/// Goes through the system's getaddrinfo, as Node's `lookup` does, so the
+/// hosts file, mDNS and other NSS sources apply; errors use its codes.
+pub async fn lookup(hostname: &str, family: Option<u8>) -> Result<Vec<IpAddr>, DnsError> {
+    let want = |ip: &IpAddr| match family {
+        Some(4) => ip.is_ipv4(),
+        Some(6) => ip.is_ipv6(),
+        _ => true,
+    };
+    let found = tokio::net::lookup_host((hostname, 0)).await.map_err(|e| {
+        // std only passes on gai_strerror's text, not the EAI_* value.
+        let code = if e.to_string().contains("Temporary failure") {
+            "EAI_AGAIN"
+        } else {
+            "ENOTFOUND"
+        };
+        DnsError::new(code, format!("getaddrinfo {code} {hostname}"))
+    })?;
+    let mut addrs: Vec<IpAddr> = Vec::new();
+    for ip in found.map(|addr| addr.ip()).filter(want) {
+        // One entry per socket type comes back for each address.
+        if !addrs.contains(&ip) {
+            addrs.push(ip);
+        }
+    }
+    if addrs.is_empty() {
+        return Err(DnsError::new(
+            "ENOTFOUND",
+            format!("getaddrinfo ENOTFOUND {hostname}"),
+        ));
+    }
+    Ok(addrs)
+}
+
+/// `dns.resolve`: records of `rrtype`, shaped like Node's results (strings for
+/// A/AAAA/CNAME/NS, objects for MX/SRV, chunk arrays for TXT).
+pub async fn resolve(hostname: &str, rrtype: &str) -> Result<Vec<Value>, DnsError> {
+    let record_type = match rrtype.to_ascii_uppercase().as_str() {
+        "A" => RecordType::A,
+        "AAAA" => RecordType::AAAA,
+        "MX" => RecordType::MX,
+        "TXT" => RecordType::TXT,
+        "SRV" => RecordType::SRV,
+        "CNAME" => RecordType::CNAME,
+        "NS" => RecordType::NS,
+        _ => {
+            return Err(DnsError::new(
+                "EBADQUERY",
+                format!("unsupported rrtype: {rrtype}"),
+            ))
+        }
+    };
+    let syscall = format!("query{}", rrtype_label(record_type));
+    let name = Name::from_utf8(hostname)
+        .map_err(|_| DnsError::new("EBADNAME", format!("{syscall} EBADNAME {hostname}")))?;
+    let found = resolver()?.lookup(name, record_type).await.map_err(|e| {
+        let code = resolve_code(&e);
+        DnsError::new(code, format!("{syscall} {code} {hostname}"))
+    })?;
+
+    // CNAME chains come back alongside the requested records; keep only those.
+    let records: Vec<Value> = found
+        .iter()
+        .filter(|rdata| rdata.record_type() == record_type)
+        .filter_map(|rdata| match rdata {
+            RData::A(a) => Some(json!(a.0.to_string())),
+            RData::AAAA(aaaa) => Some(json!(aaaa.0.to_string())),
+            RData::MX(mx) => Some(json!({
+                "exchange": name_text(mx.exchange()),
+                "priority": mx.preference(),
+            })),
+            RData::TXT(txt) => Some(json!(txt
+                .iter()
+                .map(|chunk| String::from_utf8_lossy(chunk).into_owned())
+                .collect::<Vec<_>>())),
+            RData::SRV(srv) => Some(json!({
+                "name": name_text(srv.target()),
+                "port": srv.port(),
+                "priority": srv.priority(),
+                "weight": srv.weight(),
+            })),
+            RData::CNAME(cname) => Some(json!(name_text(&cname.0))),
+            RData::NS(ns) => Some(json!(name_text(&ns.0))),
+            _ => None,
+        })
+        .collect();
+    if records.is_empty() {
+        return Err(DnsError::new(
+            "ENODATA",
+            format!("{syscall} ENODATA {hostname}"),
+        ));
+    }
+    Ok(records)
+}
+
+fn resolve_code(err: &ResolveError) -> &'static str {
+    match err.kind() {
+        ResolveErrorKind::NoRecordsFound { response_code, .. } => match *response_code {
+            ResponseCode::NXDomain => "ENOTFOUND",
+            ResponseCode::NoError => "ENODATA",
+            ResponseCode::ServFail => "ESERVFAIL",
+            ResponseCode::Refused => "EREFUSED",
+            ResponseCode::FormErr => "EFORMERR",
+            ResponseCode::NotImp => "ENOTIMP",
+            _ => "EBADRESP",
+        },
+        ResolveErrorKind::Timeout => "ETIMEOUT",
+        ResolveErrorKind::NoConnections | ResolveErrorKind::Io(_) | ResolveErrorKind::Proto(_) => {
+            "ECONNREFUSED"
+        }
+        _ => "EBADRESP",
+    }
+}
+
+/// Node's syscall names: `queryMx`, `querySrv`, ...
+fn rrtype_label(record_type: RecordType) -> &'static str {
+    match record_type {
+        RecordType::A => "A",
+        RecordType::AAAA => "Aaaa",
+        RecordType::MX => "Mx",
+        RecordType::TXT => "Txt",
+        RecordType::SRV => "Srv",
+        RecordType::CNAME => "Cname",
+        _ => "Ns",
+    }
+}
+
+fn name_text(name: &Name) -> String {
+    name.to_utf8().trim_end_matches('.').to_string()
+}
@@ -1,33 +1,116 @@
-use std::collections::HashMap;
+mod auth;
+mod config;
+mod cookies;
+mod dns;
+mod policy;
+mod session;
+mod tls;
+
+use std::collections::{HashMap, HashSet};
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
+use auth::{origin_allowed, request_token, token_matches, AUTH_TIMEOUT, CLOSE_UNAUTHORIZED};
 use base64::{engine::general_purpose, Engine as _};
-use futures_util::{SinkExt, StreamExt};
+use clap::Parser;
+use config::{Cli, Config, Features};
+use futures_util::stream::SplitSink;
+use futures_util::{SinkExt, Stream, StreamExt};
+use policy::PinnedResolver;
 use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
 use serde::{Deserialize, Serialize};
+use session::{ReplayLog, SessionRequest};
+use tls::ServerStream;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::{mpsc, Mutex};
-use tokio_tungstenite::tungstenite::Message;
-use tokio_tungstenite::accept_async;
-use tokio_rustls::TlsConnector;
-use tokio_rustls::rustls::{
-    self,
-    client::{ServerCertVerified, ServerCertVerifier},
-    Certificate, ClientConfig, Error as TlsError, ServerName,
+use tokio::net::{TcpListener, TcpSocket, TcpStream, UdpSocket};
+use tokio::sync::{mpsc, oneshot, watch, Mutex, OwnedSemaphorePermit, Semaphore};
+use tokio::task::{AbortHandle, JoinHandle};
+use tokio_rustls::rustls::ServerName;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+use tokio_tungstenite::tungstenite::handshake::server::{
+    ErrorResponse as HandshakeRejection, Request, Response,
 };
+use tokio_tungstenite::tungstenite::http::StatusCode;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::{CloseFrame, WebSocketConfig};
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+use tokio_tungstenite::{accept_hdr_async_with_config, WebSocketStream};
 
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct FetchRequest {
-    r#type: String,
     id: u64,
     url: String,
     method: Option<String>,
-    headers: Option<HashMap<String, String>>,
+    headers: Option<RequestHeaders>,
     body: Option<String>,
     body_encoding: Option<String>,
+    /// Deliver the response as `fetch_head`/`fetch_chunk`/`fetch_end` frames
+    /// instead of a single buffered `fetch` message.
+    stream: Option<bool>,
+    /// The body follows as `fetch_body_chunk` messages terminated by
+    /// `fetch_body_end`; `body` is ignored.
+    body_stream: Option<bool>,
+    /// Client certificate and roots for TLS, as on `tcp_open`.
+    cert: Option<String>,
+    key: Option<String>,
+    client_cert: Option<String>,
+    ca: Option<String>,
+    /// `follow` (the default), `manual` to get 3xx responses as they are, or
+    /// `error` to fail on them.
+    redirect: Option<String>,
+    /// Redirects `follow` takes before failing; `MAX_REDIRECTS` by default.
+    max_redirects: Option<usize>,
+    connect_timeout_ms: Option<u64>,
+    /// Deadline for the whole exchange, response body included.
+    timeout_ms: Option<u64>,
+    /// Largest response body accepted, counted after decompression.
+    max_body_size: Option<usize>,
+    /// `true` for the session's cookie jar or a profile name; cookies are
+    /// then sent and stored on every hop.
+    cookie_jar: Option<cookies::JarName>,
+    #[serde(skip)]
+    upload: Option<reqwest::Body>,
+}
+
+/// `fetch` headers: an object, or `[name, value]` pairs to repeat a name.
+/// Values are byte strings, one byte per char up to U+00FF, as in `Headers`.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum RequestHeaders {
+    Map(HashMap<String, String>),
+    List(Vec<(String, String)>),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FetchBodyChunkRequest {
+    id: u64,
+    data: Option<String>,
+    data_encoding: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FetchBodyEndRequest {
+    id: u64,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct FetchBodyAckMessage {
+    r#type: String,
+    id: u64,
+    ok: bool,
+    error: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FetchAbortRequest {
+    id: u64,
 }
 
 #[derive(Debug, Serialize)]
@@ -36,28 +119,84 @@ struct FetchResponse {
     r#type: String,
     id: u64,
     status: u16,
-    headers: HashMap<String, String>,
+    #[serde(flatten)]
+    headers: ResponseHeaders,
     body: Option<String>,
     body_encoding: Option<String>,
     error: Option<String>,
+    /// Set for timeouts and exceeded limits; see `FetchFailure`.
+    code: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct FetchHeadMessage {
+    r#type: String,
+    id: u64,
+    status: u16,
+    #[serde(flatten)]
+    headers: ResponseHeaders,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct FetchChunkMessage {
+    r#type: String,
+    id: u64,
+    data: String,
+    data_encoding: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct FetchEndMessage {
+    r#type: String,
+    id: u64,
+    error: Option<String>,
+    code: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct TcpOpenRequest {
-    r#type: String,
     id: u64,
     host: String,
     port: u16,
     tls: Option<bool>,
+    #[serde(flatten)]
+    options: TlsConnectOptions,
+}
+
+/// How `tcp_open` and `tcp_starttls` set up TLS, after `tls.connect()`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TlsConnectOptions {
     server_name: Option<String>,
     insecure: Option<bool>,
+    /// Client certificate chain and key, PEM, for mutual TLS.
+    cert: Option<String>,
+    key: Option<String>,
+    /// Names a client certificate from `[tls.client_certs]` instead.
+    client_cert: Option<String>,
+    /// PEM roots to verify the server against instead of the defaults.
+    ca: Option<String>,
+    /// ALPN protocol ids to offer, in order of preference.
+    alpn_protocols: Option<Vec<String>>,
+}
+
+/// Upgrades an open plain stream to TLS, as `tls.connect({ socket })` does.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TcpStartTlsRequest {
+    id: u64,
+    stream_id: u64,
+    #[serde(flatten)]
+    options: TlsConnectOptions,
 }
 
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct TcpWriteRequest {
-    r#type: String,
     id: u64,
     stream_id: u64,
     data: Option<String>,
@@ -67,588 +206,3373 @@ struct TcpWriteRequest {
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct TcpCloseRequest {
-    r#type: String,
+    stream_id: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TcpEndRequest {
     id: u64,
     stream_id: u64,
 }
 
+/// `tcp_pause` / `tcp_resume`. Like Node's `socket.pause()` there is no reply;
+/// unknown streams are ignored.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TcpFlowRequest {
+    stream_id: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TcpListenRequest {
+    id: u64,
+    host: Option<String>,
+    port: u16,
+    backlog: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TcpUnlistenRequest {
+    id: u64,
+    listener_id: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct UdpBindRequest {
+    id: u64,
+    /// `udp4` (default) or `udp6`, as in `dgram.createSocket`.
+    family: Option<String>,
+    host: Option<String>,
+    port: Option<u16>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct UdpSendRequest {
+    id: u64,
+    socket_id: u64,
+    host: String,
+    port: u16,
+    data: Option<String>,
+    data_encoding: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct UdpCloseRequest {
+    socket_id: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DnsLookupRequest {
+    id: u64,
+    hostname: String,
+    /// 4 or 6; anything else returns both families.
+    family: Option<u8>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DnsResolveRequest {
+    id: u64,
+    hostname: String,
+    /// Defaults to `A`, as in `dns.resolve`.
+    rrtype: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BinaryFramesRequest {
+    id: u64,
+    enabled: bool,
+}
+
 #[derive(Debug, Serialize)]
 #[serde(rename_all = "camelCase")]
-struct TcpOpenResponse {
+struct BinaryFramesResponse {
     r#type: String,
     id: u64,
-    stream_id: Option<u64>,
     ok: bool,
-    error: Option<String>,
+    enabled: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CookieListRequest {
+    id: u64,
+    cookie_jar: cookies::JarName,
+    /// Only the cookies a request to this URL would carry.
+    url: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CookieSetRequest {
+    id: u64,
+    cookie_jar: cookies::JarName,
+    /// The URL the cookie is set as if from, for its default domain and path.
+    url: String,
+    /// A `Set-Cookie` header value.
+    cookie: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CookieClearRequest {
+    id: u64,
+    cookie_jar: cookies::JarName,
+    /// Only cookies for this domain and its subdomains.
+    domain: Option<String>,
 }
 
 #[derive(Debug, Serialize)]
 #[serde(rename_all = "camelCase")]
-struct TcpWriteResponse {
+struct CookieListResponse {
     r#type: String,
     id: u64,
     ok: bool,
+    cookies: Vec<cookies::CookieInfo>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     error: Option<String>,
 }
 
+/// Reply to `cookie_set` and `cookie_clear`.
 #[derive(Debug, Serialize)]
 #[serde(rename_all = "camelCase")]
-struct TcpDataMessage {
+struct CookieResponse {
     r#type: String,
-    stream_id: u64,
-    data: String,
-    data_encoding: String,
+    id: u64,
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AuthRequest {
+    id: u64,
+    token: String,
 }
 
 #[derive(Debug, Serialize)]
 #[serde(rename_all = "camelCase")]
-struct TcpCloseMessage {
+struct AuthResponse {
     r#type: String,
-    stream_id: u64,
-    error: Option<String>,
+    id: u64,
+    ok: bool,
 }
 
-struct NoVerifier;
-
-impl ServerCertVerifier for NoVerifier {
-    fn verify_server_cert(
-        &self,
-        _end_entity: &Certificate,
-        _intermediates: &[Certificate],
-        _server_name: &ServerName,
-        _scts: &mut dyn Iterator<Item = &[u8]>,
-        _ocsp_response: &[u8],
-        _now: std::time::SystemTime,
-    ) -> Result<ServerCertVerified, TlsError> {
-        Ok(ServerCertVerified::assertion())
-    }
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct HelloRequest {
+    id: u64,
+    protocol_version: Option<String>,
+    features: Option<Vec<String>>,
+}
 
-    fn verify_tls12_signature(
-        &self,
-        _message: &[u8],
-        _cert: &Certificate,
-        _dss: &rustls::DigitallySignedStruct,
-    ) -> Result<rustls::client::HandshakeSignatureValid, TlsError> {
-        Ok(rustls::client::HandshakeSignatureValid::assertion())
-    }
+/// Sent unprompted as the first message of every session.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ServerHello {
+    r#type: String,
+    protocol_version: String,
+    server: String,
+    messages: Vec<&'static str>,
+    codecs: Vec<&'static str>,
+    features: Vec<&'static str>,
+    limits: ProtocolLimits,
+    /// The session stays closed to everything but `auth` until a token is
+    /// presented, unless one came with the handshake.
+    auth_required: bool,
+}
 
-    fn verify_tls13_signature(
-        &self,
-        _message: &[u8],
-        _cert: &Certificate,
-        _dss: &rustls::DigitallySignedStruct,
-    ) -> Result<rustls::client::HandshakeSignatureValid, TlsError> {
-        Ok(rustls::client::HandshakeSignatureValid::assertion())
-    }
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ProtocolLimits {
+    max_message_size: usize,
+    max_frame_size: usize,
+    max_streams: usize,
+    max_fetches: usize,
+    max_listeners: usize,
+    max_udp_sockets: usize,
+    tcp_read_chunk: usize,
+    upload_queue_chunks: usize,
+    outbound_queue_bytes: usize,
+    resume_timeout_secs: u64,
+    resume_buffer_bytes: usize,
 }
 
-fn make_tls_config(insecure: bool) -> Result<ClientConfig, String> {
-    if insecure {
-        let cfg = ClientConfig::builder()
-            .with_safe_defaults()
-            .with_custom_certificate_verifier(Arc::new(NoVerifier))
-            .with_no_client_auth();
-        return Ok(cfg);
-    }
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct HelloResponse {
+    r#type: String,
+    id: u64,
+    ok: bool,
+    protocol_version: String,
+    features: Vec<String>,
+}
 
-    let mut root_store = rustls::RootCertStore::empty();
-    root_store.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
-        rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
-            ta.subject,
-            ta.spki,
-            ta.name_constraints,
-        )
-    }));
+/// Sent after authentication on connections that asked for a resumable
+/// session, before any of the session's own frames. Those are numbered from
+/// 1 across every connection the session has; `received` counts the client
+/// frames the session has handled.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SessionMessage {
+    r#type: String,
+    token: String,
+    resumed: bool,
+    received: u64,
+    /// Why a requested resume fell back to a fresh session.
+    error: Option<String>,
+}
 
-    let cfg = ClientConfig::builder()
-        .with_safe_defaults()
-        .with_root_certificates(root_store)
-        .with_no_client_auth();
-    Ok(cfg)
+/// Lets the proxy drop replay frames the client has seen; no reply.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SessionAckRequest {
+    received: u64,
 }
 
-enum StreamWriter {
-    Plain(tokio::net::tcp::OwnedWriteHalf),
-    Tls(tokio::io::WriteHalf<tokio_rustls::client::TlsStream<TcpStream>>),
+/// Reply to a request whose message type is disabled on this proxy.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ErrorResponse {
+    r#type: String,
+    id: u64,
+    ok: bool,
+    error: String,
 }
 
-fn decode_body(body: &Option<String>, encoding: &Option<String>) -> Result<Vec<u8>, String> {
-    let Some(body) = body else { return Ok(Vec::new()); };
-    match encoding.as_deref() {
-        Some("base64") => general_purpose::STANDARD
-            .decode(body)
-            .map_err(|e| format!("base64 decode error: {e}")),
-        Some("json") | Some("utf8") | None => Ok(body.as_bytes().to_vec()),
-        Some(other) => Err(format!("unsupported body encoding: {other}")),
-    }
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TcpOpenResponse {
+    r#type: String,
+    id: u64,
+    stream_id: Option<u64>,
+    ok: bool,
+    error: Option<String>,
+    /// `EGRESS_DENIED` when the egress rules refused the destination.
+    code: Option<String>,
+    /// Handshake details, for `tls: true` streams.
+    tls: Option<tls::TlsInfo>,
 }
 
-fn encode_body(bytes: &[u8]) -> (Option<String>, Option<String>) {
-    if bytes.is_empty() {
-        return (None, None);
-    }
-    let b64 = general_purpose::STANDARD.encode(bytes);
-    (Some(b64), Some("base64".to_string()))
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TcpStartTlsResponse {
+    r#type: String,
+    id: u64,
+    ok: bool,
+    error: Option<String>,
+    tls: Option<tls::TlsInfo>,
 }
 
-fn header_map_from_hash(headers: &Option<HashMap<String, String>>) -> Result<HeaderMap, String> {
-    let mut out = HeaderMap::new();
-    if let Some(headers) = headers {
-        for (k, v) in headers {
-            let name = HeaderName::from_bytes(k.as_bytes())
-                .map_err(|e| format!("invalid header name {k}: {e}"))?;
-            let value = HeaderValue::from_str(v)
-                .map_err(|e| format!("invalid header value {k}: {e}"))?;
-            out.insert(name, value);
-        }
-    }
-    Ok(out)
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TcpWriteResponse {
+    r#type: String,
+    id: u64,
+    ok: bool,
+    error: Option<String>,
 }
 
-fn headers_to_hash(headers: &HeaderMap) -> HashMap<String, String> {
-    let mut out = HashMap::new();
-    for (k, v) in headers.iter() {
-        if let Ok(val) = v.to_str() {
-            out.insert(k.as_str().to_string(), val.to_string());
-        }
-    }
-    out
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TcpEndResponse {
+    r#type: String,
+    id: u64,
+    ok: bool,
+    error: Option<String>,
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let addr: SocketAddr = "127.0.0.1:5772".parse()?;
-    let listener = TcpListener::bind(addr).await?;
-    println!("WS proxy listening on ws://{addr}");
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TcpDataMessage {
+    r#type: String,
+    stream_id: u64,
+    data: String,
+    data_encoding: String,
+}
 
-    loop {
-        let (stream, _) = listener.accept().await?;
-        tokio::spawn(async move {
-            let ws_stream = match accept_async(stream).await {
-                Ok(ws) => ws,
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TcpCloseMessage {
+    r#type: String,
+    stream_id: u64,
+    /// `eof`, `error`, `closed` (by the client) or `session`.
+    reason: String,
+    error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TcpListenResponse {
+    r#type: String,
+    id: u64,
+    listener_id: Option<u64>,
+    ok: bool,
+    /// Bound address; the port differs from the request when it asked for 0.
+    local_address: Option<String>,
+    local_port: Option<u16>,
+    error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TcpUnlistenResponse {
+    r#type: String,
+    id: u64,
+    ok: bool,
+    error: Option<String>,
+}
+
+/// A connection accepted on a `tcp_listen` socket. The stream then behaves
+/// like one opened with `tcp_open`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TcpAcceptMessage {
+    r#type: String,
+    listener_id: u64,
+    stream_id: u64,
+    remote_address: String,
+    remote_port: u16,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct UdpBindResponse {
+    r#type: String,
+    id: u64,
+    socket_id: Option<u64>,
+    ok: bool,
+    local_address: Option<String>,
+    local_port: Option<u16>,
+    error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct UdpSendResponse {
+    r#type: String,
+    id: u64,
+    ok: bool,
+    error: Option<String>,
+    /// As on `tcp_open`.
+    code: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct UdpMessage {
+    r#type: String,
+    socket_id: u64,
+    data: String,
+    data_encoding: String,
+    remote_address: String,
+    remote_port: u16,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct UdpCloseMessage {
+    r#type: String,
+    socket_id: u64,
+    /// `error`, `closed` (by the client) or `session`.
+    reason: String,
+    error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct DnsAddress {
+    address: String,
+    family: u8,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct DnsLookupResponse {
+    r#type: String,
+    id: u64,
+    ok: bool,
+    addresses: Vec<DnsAddress>,
+    error: Option<String>,
+    /// Node error code such as `ENOTFOUND`, for `err.code`.
+    code: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct DnsResolveResponse {
+    r#type: String,
+    id: u64,
+    ok: bool,
+    records: Vec<serde_json::Value>,
+    error: Option<String>,
+    code: Option<String>,
+}
+
+const PROTOCOL_VERSION: &str = "1.0.0";
+
+/// Message types this build accepts from clients.
+const PROTOCOL_MESSAGES: &[&str] = &[
+    "auth",
+    "hello",
+    "fetch",
+    "fetch_abort",
+    "fetch_body_chunk",
+    "fetch_body_end",
+    "tcp_open",
+    "tcp_write",
+    "tcp_end",
+    "tcp_close",
+    "tcp_pause",
+    "tcp_resume",
+    "tcp_starttls",
+    "tcp_listen",
+    "tcp_unlisten",
+    "udp_bind",
+    "udp_send",
+    "udp_close",
+    "dns_lookup",
+    "dns_resolve",
+    "cookie_list",
+    "cookie_set",
+    "cookie_clear",
+    "binary_frames",
+    "session_ack",
+];
+
+/// Whether `msg_type` is served under the configured feature toggles.
+fn message_enabled(features: &Features, msg_type: &str) -> bool {
+    if msg_type.starts_with("fetch") || msg_type.starts_with("cookie_") {
+        return features.fetch;
+    }
+    if msg_type == "tcp_listen" || msg_type == "tcp_unlisten" {
+        return features.tcp && features.tcp_listen;
+    }
+    if msg_type.starts_with("tcp_") {
+        return features.tcp;
+    }
+    if msg_type.starts_with("udp_") {
+        return features.udp;
+    }
+    if msg_type.starts_with("dns_") {
+        return features.dns;
+    }
+    if msg_type == "binary_frames" {
+        return features.binary_frames;
+    }
+    if msg_type.starts_with("session_") {
+        return features.resume;
+    }
+    true
+}
+
+fn server_hello(config: &Config, auth_required: bool) -> ServerHello {
+    let mut codecs = vec!["json"];
+    let mut features = Vec::new();
+    if config.features.binary_frames {
+        codecs.push("binary");
+        features.push("binaryFrames");
+    }
+    if config.features.resume {
+        features.push("resume");
+    }
+    ServerHello {
+        r#type: "hello".to_string(),
+        protocol_version: PROTOCOL_VERSION.to_string(),
+        server: format!("{}/{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+        messages: PROTOCOL_MESSAGES
+            .iter()
+            .copied()
+            .filter(|m| message_enabled(&config.features, m))
+            .collect(),
+        codecs,
+        features,
+        limits: ProtocolLimits {
+            max_message_size: config.limits.max_message_size,
+            max_frame_size: config.limits.max_frame_size,
+            max_streams: config.limits.max_streams,
+            max_fetches: config.limits.max_fetches,
+            max_listeners: config.limits.max_listeners,
+            max_udp_sockets: config.limits.max_udp_sockets,
+            tcp_read_chunk: config.limits.tcp_read_chunk,
+            upload_queue_chunks: config.limits.upload_queue_chunks,
+            outbound_queue_bytes: config.limits.outbound_queue_bytes,
+            resume_timeout_secs: config.limits.resume_timeout_secs,
+            resume_buffer_bytes: config.limits.resume_buffer_bytes,
+        },
+        auth_required,
+    }
+}
+
+enum StreamReader {
+    Plain(tokio::net::tcp::OwnedReadHalf),
+    Tls(tokio::io::ReadHalf<tokio_rustls::client::TlsStream<TcpStream>>),
+}
+
+enum StreamWriter {
+    Plain(tokio::net::tcp::OwnedWriteHalf),
+    Tls(tokio::io::WriteHalf<tokio_rustls::client::TlsStream<TcpStream>>),
+}
+
+/// What a stream's reader is told to do with its socket.
+enum ReaderStop {
+    /// Close the stream, giving this `tcp_close` reason.
+    Close(&'static str),
+    /// Return the read half and exit quietly, for `tcp_starttls`.
+    Handback(oneshot::Sender<StreamReader>),
+}
+
+/// Work for a stream's writer task, done in the order it was queued.
+enum WriteOp {
+    /// Write the bytes, then answer `tcp_write` request `id`.
+    Write { id: u64, data: Vec<u8> },
+    /// Half-close, then answer `tcp_end` request `id`.
+    End { id: u64 },
+    /// Return the write half and exit quietly, for `tcp_starttls`.
+    Handback(oneshot::Sender<StreamWriter>),
+}
+
+/// Writes a stream may have queued before more are refused.
+const STREAM_WRITE_QUEUE: usize = 16;
+
+struct StreamEntry {
+    /// Queue of the stream's writer task.
+    writes: mpsc::Sender<WriteOp>,
+    /// Never sent: dropping it with the entry stops the writer task, even in
+    /// the middle of a write to a peer that is not reading.
+    _writer_stop: oneshot::Sender<()>,
+    /// Set once the stream runs TLS, which cannot be started twice.
+    tls: bool,
+    /// The host `tcp_open` connected to (or the peer address of an accepted
+    /// stream), the default `serverName` for `tcp_starttls`.
+    host: String,
+    /// Set by `tcp_pause`. The reader stops reading while it is, so the
+    /// remote sender is throttled by TCP itself.
+    paused: watch::Sender<bool>,
+    /// Set by `tcp_end`; later writes are refused.
+    ended: bool,
+    /// Stops the reader, which then emits the stream's final `tcp_close`.
+    /// Dropping it (with the session's table) counts as `session`.
+    close: oneshot::Sender<ReaderStop>,
+}
+
+/// The reader's ends of a `StreamEntry`'s controls.
+struct ReaderControl {
+    paused: watch::Receiver<bool>,
+    close: oneshot::Receiver<ReaderStop>,
+}
+
+type StreamTable = Arc<Mutex<HashMap<u64, StreamEntry>>>;
+
+/// A table entry for a stream, with its writer task started.
+fn stream_entry(
+    writer: StreamWriter,
+    host: String,
+    out_tx: Outbox,
+) -> (StreamEntry, ReaderControl) {
+    let (writes, writes_rx) = mpsc::channel(STREAM_WRITE_QUEUE);
+    let (writer_stop, stop_rx) = oneshot::channel();
+    let tls = matches!(writer, StreamWriter::Tls(_));
+    spawn_writer_task(writer, writes_rx, stop_rx, out_tx);
+    let (paused, paused_rx) = watch::channel(false);
+    let (close, close_rx) = oneshot::channel();
+    let entry = StreamEntry {
+        writes,
+        _writer_stop: writer_stop,
+        tls,
+        host,
+        paused,
+        ended: false,
+        close,
+    };
+    let control = ReaderControl {
+        paused: paused_rx,
+        close: close_rx,
+    };
+    (entry, control)
+}
+
+/// A queued frame and the share of the byte budget it holds until written.
+type Outgoing = (Message, Option<OwnedSemaphorePermit>);
+
+/// Frames queued for the session's WebSocket writer task. Replies and bulk
+/// data first take their size from a per-session byte budget that comes back
+/// once the frame is on the wire, so a slow client stalls the producers
+/// instead of growing the queue. Only notices bounded by the session's limits,
+/// such as `tcp_close`, go in as they are.
+#[derive(Clone)]
+struct Outbox {
+    tx: mpsc::UnboundedSender<Outgoing>,
+    budget: Arc<Semaphore>,
+    budget_bytes: usize,
+}
+
+impl Outbox {
+    fn new(budget_bytes: usize) -> (Self, mpsc::UnboundedReceiver<Outgoing>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let budget_bytes = budget_bytes.min(u32::MAX as usize);
+        let outbox = Self {
+            tx,
+            budget: Arc::new(Semaphore::new(budget_bytes)),
+            budget_bytes,
+        };
+        (outbox, rx)
+    }
+
+    fn send(&self, msg: Message) -> bool {
+        self.send_with(msg, None)
+    }
+
+    fn send_with(&self, msg: Message, credit: Option<OwnedSemaphorePermit>) -> bool {
+        self.tx.send((msg, credit)).is_ok()
+    }
+
+    /// Waits until `bytes` fit in the budget. Frames larger than the whole
+    /// budget wait for an empty queue.
+    async fn reserve(&self, bytes: usize) -> Option<OwnedSemaphorePermit> {
+        let permits = bytes.clamp(1, self.budget_bytes) as u32;
+        self.budget.clone().acquire_many_owned(permits).await.ok()
+    }
+
+    /// Queues a data frame once the budget has room for it; false once the
+    /// session is gone.
+    async fn send_data(&self, msg: Message) -> bool {
+        let credit = self.reserve(msg.len()).await;
+        self.send_with(msg, credit)
+    }
+}
+
+fn send_json<T: Serialize>(out_tx: &Outbox, msg: &T) {
+    out_tx.send(Message::Text(serde_json::to_string(msg).unwrap()));
+}
+
+/// Queues the answer to a request once the byte budget has room for it, so
+/// a client that stops reading replies stops having its requests read.
+async fn send_reply<T: Serialize>(out_tx: &Outbox, msg: &T) {
+    out_tx
+        .send_data(Message::Text(serde_json::to_string(msg).unwrap()))
+        .await;
+}
+
+// Binary frames carry raw stream bytes without the JSON/base64 envelope:
+//
+//   [kind: u8][stream id: u64 BE][request id: u64 BE][payload...]
+//
+// The request id correlates `tcp_write` acks and is 0 on `tcp_data`. JSON
+// text never starts with these kind bytes, so both formats can share a socket.
+const FRAME_TCP_DATA: u8 = 0x01;
+const FRAME_TCP_WRITE: u8 = 0x02;
+const FRAME_HEADER_LEN: usize = 17;
+
+struct BinaryFrame<'a> {
+    kind: u8,
+    stream_id: u64,
+    id: u64,
+    payload: &'a [u8],
+}
+
+fn encode_frame(kind: u8, stream_id: u64, id: u64, payload: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(FRAME_HEADER_LEN + payload.len());
+    out.push(kind);
+    out.extend_from_slice(&stream_id.to_be_bytes());
+    out.extend_from_slice(&id.to_be_bytes());
+    out.extend_from_slice(payload);
+    out
+}
+
+fn decode_frame(bin: &[u8]) -> Option<BinaryFrame<'_>> {
+    let kind = *bin.first()?;
+    if !matches!(kind, FRAME_TCP_DATA | FRAME_TCP_WRITE) || bin.len() < FRAME_HEADER_LEN {
+        return None;
+    }
+    Some(BinaryFrame {
+        kind,
+        stream_id: u64::from_be_bytes(bin[1..9].try_into().unwrap()),
+        id: u64::from_be_bytes(bin[9..17].try_into().unwrap()),
+        payload: &bin[FRAME_HEADER_LEN..],
+    })
+}
+
+fn tcp_data_message(stream_id: u64, data: &[u8], binary: bool) -> Message {
+    if binary {
+        return Message::Binary(encode_frame(FRAME_TCP_DATA, stream_id, 0, data));
+    }
+    let msg = TcpDataMessage {
+        r#type: "tcp_data".to_string(),
+        stream_id,
+        data: general_purpose::STANDARD.encode(data),
+        data_encoding: "base64".to_string(),
+    };
+    Message::Text(serde_json::to_string(&msg).unwrap())
+}
+
+/// Forwards what a stream reads as `tcp_data` until the peer closes, a read
+/// fails or the entry's `close` fires. Whichever comes first, the reader
+/// drops its half of the socket and sends the one `tcp_close` for the
+/// stream, so nothing for it can follow. A handback instead returns the
+/// read half and sends nothing.
+fn spawn_reader(
+    mut reader: StreamReader,
+    stream_id: u64,
+    out_tx: Outbox,
+    streams: StreamTable,
+    binary_frames: Arc<AtomicBool>,
+    read_chunk: usize,
+    control: ReaderControl,
+) {
+    let ReaderControl { mut paused, close } = control;
+    tokio::spawn(async move {
+        let pump = async {
+            let mut buf = vec![0u8; read_chunk];
+            loop {
+                // Errs only once the entry is gone, and then `close` wins.
+                let _ = paused.wait_for(|paused| !paused).await;
+                let read_res = match &mut reader {
+                    StreamReader::Plain(reader) => reader.read(&mut buf).await,
+                    StreamReader::Tls(reader) => reader.read(&mut buf).await,
+                };
+                match read_res {
+                    Ok(0) => return ("eof", None),
+                    Ok(n) => {
+                        let binary = binary_frames.load(Ordering::Relaxed);
+                        let msg = tcp_data_message(stream_id, &buf[..n], binary);
+                        if !out_tx.send_data(msg).await {
+                            return ("session", None);
+                        }
+                    }
+                    Err(e) => return ("error", Some(format!("read error: {e}"))),
+                }
+            }
+        };
+        let (reason, error) = tokio::select! {
+            biased;
+            stop = close => match stop {
+                Ok(ReaderStop::Handback(tx)) => {
+                    let _ = tx.send(reader);
+                    return;
+                }
+                Ok(ReaderStop::Close(reason)) => (reason, None),
+                Err(_) => ("session", None),
+            },
+            outcome = pump => outcome,
+        };
+        drop(reader);
+        streams.lock().await.remove(&stream_id);
+        let msg = TcpCloseMessage {
+            r#type: "tcp_close".to_string(),
+            stream_id,
+            reason: reason.to_string(),
+            error,
+        };
+        send_json(&out_tx, &msg);
+    });
+}
+
+/// Carries out a stream's `WriteOp`s, answering each `tcp_write` and
+/// `tcp_end` once the socket has taken it, so a peer that stops reading only
+/// holds up its own stream. Requests still queued when the entry goes are
+/// answered as failed.
+fn spawn_writer_task(
+    mut writer: StreamWriter,
+    mut ops: mpsc::Receiver<WriteOp>,
+    stop: oneshot::Receiver<()>,
+    out_tx: Outbox,
+) {
+    tokio::spawn(async move {
+        // The request being carried out, bytes aside, in case `stop` cuts it short.
+        let mut in_flight = None;
+        let pump = async {
+            let mut ended = false;
+            while let Some(op) = ops.recv().await {
+                match op {
+                    WriteOp::Write { id, data } => {
+                        in_flight = Some(WriteOp::Write {
+                            id,
+                            data: Vec::new(),
+                        });
+                        let write_res = match &mut writer {
+                            StreamWriter::Plain(writer) => writer.write_all(&data).await,
+                            StreamWriter::Tls(writer) => writer.write_all(&data).await,
+                        };
+                        let result = write_res.map_err(|e| format!("write error: {e}"));
+                        send_reply(&out_tx, &tcp_write_response(id, result)).await;
+                    }
+                    // Ending twice is a no-op, as with `socket.end()`.
+                    WriteOp::End { id } => {
+                        in_flight = Some(WriteOp::End { id });
+                        let result = match &mut writer {
+                            _ if ended => Ok(()),
+                            StreamWriter::Plain(writer) => writer.shutdown().await,
+                            StreamWriter::Tls(writer) => writer.shutdown().await,
+                        };
+                        ended = true;
+                        let result = result.map_err(|e| format!("shutdown error: {e}"));
+                        send_reply(&out_tx, &tcp_end_response(id, result)).await;
+                    }
+                    WriteOp::Handback(tx) => return Some(tx),
+                }
+                in_flight = None;
+            }
+            None
+        };
+        let handback = tokio::select! {
+            biased;
+            _ = stop => None,
+            handback = pump => handback,
+        };
+        if let Some(tx) = handback {
+            let _ = tx.send(writer);
+            return;
+        }
+        drop(writer);
+        let unfinished = in_flight
+            .into_iter()
+            .chain(std::iter::from_fn(|| ops.try_recv().ok()));
+        for op in unfinished {
+            let error = Err("stream closed".to_string());
+            match op {
+                WriteOp::Write { id, .. } => {
+                    send_reply(&out_tx, &tcp_write_response(id, error)).await
+                }
+                WriteOp::End { id } => send_reply(&out_tx, &tcp_end_response(id, error)).await,
+                WriteOp::Handback(_) => {}
+            }
+        }
+    });
+}
+
+/// Queues `data` for the stream's writer task, which answers `tcp_write`
+/// request `id`. An error is the answer to send instead.
+async fn write_stream(
+    streams: &StreamTable,
+    stream_id: u64,
+    id: u64,
+    data: Vec<u8>,
+) -> Result<(), String> {
+    let guard = streams.lock().await;
+    let Some(entry) = guard.get(&stream_id) else {
+        return Err("unknown stream".to_string());
+    };
+    if entry.ended {
+        return Err("write after end".to_string());
+    }
+    entry
+        .writes
+        .try_send(WriteOp::Write { id, data })
+        .map_err(write_queue_error)
+}
+
+/// Half-closes a stream once the writes queued before it are out: a FIN
+/// (after a TLS `close_notify`) goes to the peer while the reader keeps
+/// delivering `tcp_data` until the peer closes too. As with `write_stream`,
+/// the writer task answers unless this returns an error.
+async fn end_stream(streams: &StreamTable, stream_id: u64, id: u64) -> Result<(), String> {
+    let mut guard = streams.lock().await;
+    let Some(entry) = guard.get_mut(&stream_id) else {
+        return Err("unknown stream".to_string());
+    };
+    entry
+        .writes
+        .try_send(WriteOp::End { id })
+        .map_err(write_queue_error)?;
+    entry.ended = true;
+    Ok(())
+}
+
+fn write_queue_error(e: mpsc::error::TrySendError<WriteOp>) -> String {
+    match e {
+        mpsc::error::TrySendError::Full(_) => "write queue full".to_string(),
+        mpsc::error::TrySendError::Closed(_) => "stream closed".to_string(),
+    }
+}
+
+/// How long a TLS handshake, on `tcp_open` or `tcp_starttls`, may take.
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// A TLS client set up from a request's options, ready to handshake.
+struct TlsClient {
+    connector: TlsConnector,
+    server_name: ServerName,
+    verdict: tls::Verdict,
+}
+
+impl TlsClient {
+    /// `host` is the server name unless the options give one.
+    fn new(config: &Config, host: &str, options: &TlsConnectOptions) -> Result<Self, String> {
+        let server_name = options.server_name.as_deref().unwrap_or(host);
+        let server_name =
+            ServerName::try_from(server_name).map_err(|e| format!("bad server name: {e}"))?;
+        let identity = tls::identity(
+            &config.tls,
+            options.client_cert.as_deref(),
+            options.cert.as_deref(),
+            options.key.as_deref(),
+        )?;
+        let (cfg, verdict) = tls::client_config(
+            options.insecure.unwrap_or(false),
+            options.ca.as_deref(),
+            identity.as_ref(),
+            options.alpn_protocols.as_deref().unwrap_or_default(),
+        )?;
+        Ok(Self {
+            connector: TlsConnector::from(Arc::new(cfg)),
+            server_name,
+            verdict,
+        })
+    }
+
+    async fn connect(
+        self,
+        stream: TcpStream,
+    ) -> Result<(tokio_rustls::client::TlsStream<TcpStream>, tls::TlsInfo), String> {
+        let handshake = self.connector.connect(self.server_name, stream);
+        let tls_stream = tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, handshake)
+            .await
+            .map_err(|_| "tls handshake timed out".to_string())?
+            .map_err(|e| format!("tls handshake error: {e}"))?;
+        let info = tls::session_info(tls_stream.get_ref().1, &self.verdict);
+        Ok((tls_stream, info))
+    }
+}
+
+/// A stream upgraded by `start_tls`: its new halves, host, pause state and
+/// handshake details.
+type Upgraded = (StreamReader, StreamWriter, String, bool, tls::TlsInfo);
+
+/// Takes a plain stream out of the table, gets its halves back from the
+/// reader and the writer task (after the writes queued before it, which get
+/// as long as a handshake) and runs the handshake on the reunited socket. On error, the flag
+/// says whether the stream is gone and still owes its `tcp_close`.
+async fn start_tls(
+    config: &Config,
+    streams: &StreamTable,
+    stream_id: u64,
+    options: &TlsConnectOptions,
+) -> Result<Upgraded, (String, bool)> {
+    let (client, entry) = {
+        let mut guard = streams.lock().await;
+        let entry = match guard.get(&stream_id) {
+            None => return Err(("unknown stream".to_string(), false)),
+            Some(entry) if entry.tls => return Err(("stream is already TLS".to_string(), false)),
+            Some(entry) if entry.ended => return Err(("stream has ended".to_string(), false)),
+            Some(entry) => entry,
+        };
+        let client = TlsClient::new(config, &entry.host, options).map_err(|e| (e, false))?;
+        (client, guard.remove(&stream_id).unwrap())
+    };
+    let was_paused = *entry.paused.borrow();
+
+    let (tx, rx) = oneshot::channel();
+    let reader = match entry.close.send(ReaderStop::Handback(tx)) {
+        Ok(()) => rx.await.ok(),
+        Err(_) => None,
+    };
+    // Without the read half the reader already hit EOF or an error and
+    // reported the stream closed.
+    let Some(StreamReader::Plain(reader)) = reader else {
+        return Err(("stream closed".to_string(), false));
+    };
+    let (tx, rx) = oneshot::channel();
+    let handback = async {
+        entry.writes.send(WriteOp::Handback(tx)).await.ok()?;
+        rx.await.ok()
+    };
+    let writer = tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, handback)
+        .await
+        .map_err(|_| ("timed out waiting for queued writes".to_string(), true))?;
+    let Some(StreamWriter::Plain(writer)) = writer else {
+        return Err(("stream closed".to_string(), true));
+    };
+    let stream = reader
+        .reunite(writer)
+        .map_err(|e| (format!("cannot reunite stream: {e}"), true))?;
+    let (tls_stream, info) = client.connect(stream).await.map_err(|e| (e, true))?;
+    let (reader, writer) = tokio::io::split(tls_stream);
+    Ok((
+        StreamReader::Tls(reader),
+        StreamWriter::Tls(writer),
+        entry.host,
+        was_paused,
+        info,
+    ))
+}
+
+fn tcp_write_response(id: u64, result: Result<(), String>) -> TcpWriteResponse {
+    TcpWriteResponse {
+        r#type: "tcp_write".to_string(),
+        id,
+        ok: result.is_ok(),
+        error: result.err(),
+    }
+}
+
+fn tcp_end_response(id: u64, result: Result<(), String>) -> TcpEndResponse {
+    TcpEndResponse {
+        r#type: "tcp_end".to_string(),
+        id,
+        ok: result.is_ok(),
+        error: result.err(),
+    }
+}
+
+fn tcp_listen_error(id: u64, error: String) -> TcpListenResponse {
+    TcpListenResponse {
+        r#type: "tcp_listen".to_string(),
+        id,
+        listener_id: None,
+        ok: false,
+        local_address: None,
+        local_port: None,
+        error: Some(error),
+    }
+}
+
+/// Binds like Node's `server.listen`: `SO_REUSEADDR` set, backlog 511 unless
+/// the request says otherwise.
+async fn bind_listener(host: &str, port: u16, backlog: Option<u32>) -> Result<TcpListener, String> {
+    let bare = host.trim_start_matches('[').trim_end_matches(']');
+    let addr = tokio::net::lookup_host((bare, port))
+        .await
+        .map_err(|e| format!("resolve error: {e}"))?
+        .next()
+        .ok_or_else(|| format!("resolve error: no address for {host}"))?;
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4(),
+        SocketAddr::V6(_) => TcpSocket::new_v6(),
+    }
+    .map_err(|e| format!("listen error: {e}"))?;
+    socket
+        .set_reuseaddr(true)
+        .map_err(|e| format!("listen error: {e}"))?;
+    socket
+        .bind(addr)
+        .map_err(|e| format!("listen error: {e}"))?;
+    socket
+        .listen(backlog.unwrap_or(511))
+        .map_err(|e| format!("listen error: {e}"))
+}
+
+/// Accepts connections on a `tcp_listen` socket until aborted, announcing
+/// each one with `tcp_accept` before any of its data.
+fn spawn_acceptor(
+    listener: TcpListener,
+    listener_id: u64,
+    out_tx: Outbox,
+    streams: StreamTable,
+    next_stream_id: Arc<AtomicU64>,
+    binary_frames: Arc<AtomicBool>,
+    config: Arc<Config>,
+) -> AbortHandle {
+    let task = tokio::spawn(async move {
+        loop {
+            let (stream, remote) = match listener.accept().await {
+                Ok(conn) => conn,
+                Err(e) => {
+                    // Usually fd exhaustion; back off instead of spinning.
+                    log::warn!("Accept error on listener {listener_id}: {e}");
+                    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                    continue;
+                }
+            };
+            if streams.lock().await.len() >= config.limits.max_streams {
+                log::warn!(
+                    "Dropped connection from {remote} on listener {listener_id}: too many streams"
+                );
+                continue;
+            }
+
+            let stream_id = next_stream_id.fetch_add(1, Ordering::Relaxed);
+            let (reader, writer) = stream.into_split();
+            let (entry, paused) = stream_entry(
+                StreamWriter::Plain(writer),
+                remote.ip().to_string(),
+                out_tx.clone(),
+            );
+            streams.lock().await.insert(stream_id, entry);
+            let msg = TcpAcceptMessage {
+                r#type: "tcp_accept".to_string(),
+                listener_id,
+                stream_id,
+                remote_address: remote.ip().to_string(),
+                remote_port: remote.port(),
+            };
+            send_json(&out_tx, &msg);
+            spawn_reader(
+                StreamReader::Plain(reader),
+                stream_id,
+                out_tx.clone(),
+                streams.clone(),
+                binary_frames.clone(),
+                config.limits.tcp_read_chunk,
+                paused,
+            );
+        }
+    });
+    task.abort_handle()
+}
+
+/// Largest UDP payload; anything longer is truncated by the kernel.
+const UDP_MAX_DATAGRAM: usize = 65535;
+
+/// `udp_send` requests a socket may have waiting before more are refused.
+const UDP_SEND_QUEUE: usize = 64;
+
+struct UdpEntry {
+    /// Queue of the socket's `spawn_udp_sender` task.
+    sends: mpsc::Sender<UdpSendRequest>,
+    /// Stops the reader, which then emits the socket's final `udp_close`.
+    close: oneshot::Sender<&'static str>,
+}
+
+type UdpTable = Arc<std::sync::Mutex<HashMap<u64, UdpEntry>>>;
+
+/// Addresses a client socket has sent to, the only ones it hears from.
+type UdpPeers = Arc<std::sync::Mutex<HashSet<SocketAddr>>>;
+
+fn udp_bind_error(id: u64, error: String) -> UdpBindResponse {
+    UdpBindResponse {
+        r#type: "udp_bind".to_string(),
+        id,
+        socket_id: None,
+        ok: false,
+        local_address: None,
+        local_port: None,
+        error: Some(error),
+    }
+}
+
+/// Forwards datagrams as `udp_message` until the socket fails or `close`
+/// fires, then sends the socket's one `udp_close`. With `peers`, datagrams
+/// from anyone else are dropped. ICMP errors from earlier sends surface as
+/// `ConnectionReset`/`ConnectionRefused` on some platforms and do not end the
+/// socket.
+fn spawn_udp_reader(
+    socket: Arc<UdpSocket>,
+    socket_id: u64,
+    peers: Option<UdpPeers>,
+    out_tx: Outbox,
+    sockets: UdpTable,
+    close: oneshot::Receiver<&'static str>,
+) {
+    tokio::spawn(async move {
+        let pump = async {
+            let mut buf = vec![0u8; UDP_MAX_DATAGRAM];
+            loop {
+                match socket.recv_from(&mut buf).await {
+                    Ok((_, remote))
+                        if peers
+                            .as_ref()
+                            .is_some_and(|peers| !peers.lock().unwrap().contains(&remote)) =>
+                    {
+                        log::debug!("UDP socket {socket_id}: dropped datagram from {remote}");
+                    }
+                    Ok((n, remote)) => {
+                        let msg = UdpMessage {
+                            r#type: "udp_message".to_string(),
+                            socket_id,
+                            data: general_purpose::STANDARD.encode(&buf[..n]),
+                            data_encoding: "base64".to_string(),
+                            remote_address: remote.ip().to_string(),
+                            remote_port: remote.port(),
+                        };
+                        let text = serde_json::to_string(&msg).unwrap();
+                        if !out_tx.send_data(Message::Text(text)).await {
+                            return ("session", None);
+                        }
+                    }
+                    Err(e)
+                        if matches!(
+                            e.kind(),
+                            std::io::ErrorKind::ConnectionReset
+                                | std::io::ErrorKind::ConnectionRefused
+                        ) =>
+                    {
+                        log::debug!("UDP socket {socket_id}: {e}");
+                    }
+                    Err(e) => return ("error", Some(format!("read error: {e}"))),
+                }
+            }
+        };
+        let (reason, error) = tokio::select! {
+            biased;
+            reason = close => (reason.unwrap_or("session"), None),
+            outcome = pump => outcome,
+        };
+        sockets.lock().unwrap().remove(&socket_id);
+        let msg = UdpCloseMessage {
+            r#type: "udp_close".to_string(),
+            socket_id,
+            reason: reason.to_string(),
+            error,
+        };
+        send_json(&out_tx, &msg);
+    });
+}
+
+/// `[inbound].default_host`, swapped for its IPv6 counterpart on `udp6`
+/// sockets when it is IPv4 loopback or wildcard.
+fn udp_default_host(default_host: &str, v6: bool) -> String {
+    match default_host {
+        "127.0.0.1" if v6 => "::1".to_string(),
+        "0.0.0.0" if v6 => "::".to_string(),
+        host => host.to_string(),
+    }
+}
+
+/// Answers a socket's `udp_send` requests in order, off the read loop, until
+/// its table entry goes.
+fn spawn_udp_sender(
+    socket: Arc<UdpSocket>,
+    peers: Option<UdpPeers>,
+    out_tx: Outbox,
+    config: Arc<Config>,
+) -> mpsc::Sender<UdpSendRequest> {
+    let (tx, mut rx) = mpsc::channel::<UdpSendRequest>(UDP_SEND_QUEUE);
+    tokio::spawn(async move {
+        while let Some(req) = rx.recv().await {
+            let result = udp_send(&socket, peers.as_ref(), &config.egress, &req).await;
+            send_reply(&out_tx, &udp_send_response(req.id, result)).await;
+        }
+    });
+    tx
+}
+
+/// Sends one datagram to the first policy-approved address of `host` in the
+/// socket's address family, adding it to `peers`. Errors carry the
+/// response's `code`.
+async fn udp_send(
+    socket: &UdpSocket,
+    peers: Option<&UdpPeers>,
+    egress: &config::Egress,
+    req: &UdpSendRequest,
+) -> Result<(), (String, Option<&'static str>)> {
+    let data = decode_body(&req.data, &req.data_encoding).map_err(|e| (e, None))?;
+    let local = socket
+        .local_addr()
+        .map_err(|e| (format!("send error: {e}"), None))?;
+    let addrs = policy::resolve(egress, &req.host, req.port, "udp")
+        .await
+        .map_err(|e| (e.to_string(), e.code()))?;
+    let target = addrs
+        .into_iter()
+        .find(|addr| addr.is_ipv4() == local.is_ipv4())
+        .ok_or_else(|| {
+            let family = if local.is_ipv4() { "IPv4" } else { "IPv6" };
+            (format!("no {family} address for {}", req.host), None)
+        })?;
+    // Before sending, so an answer cannot beat it.
+    if let Some(peers) = peers {
+        peers.lock().unwrap().insert(target);
+    }
+    socket
+        .send_to(&data, target)
+        .await
+        .map(|_| ())
+        .map_err(|e| (format!("send error: {e}"), None))
+}
+
+fn udp_send_response(
+    id: u64,
+    result: Result<(), (String, Option<&'static str>)>,
+) -> UdpSendResponse {
+    let (error, code) = match result {
+        Ok(()) => (None, None),
+        Err((e, code)) => (Some(e), code.map(str::to_string)),
+    };
+    UdpSendResponse {
+        r#type: "udp_send".to_string(),
+        id,
+        ok: error.is_none(),
+        error,
+        code,
+    }
+}
+
+fn decode_body(body: &Option<String>, encoding: &Option<String>) -> Result<Vec<u8>, String> {
+    let Some(body) = body else {
+        return Ok(Vec::new());
+    };
+    match encoding.as_deref() {
+        Some("base64") => general_purpose::STANDARD
+            .decode(body)
+            .map_err(|e| format!("base64 decode error: {e}")),
+        Some("json") | Some("utf8") | None => Ok(body.as_bytes().to_vec()),
+        Some(other) => Err(format!("unsupported body encoding: {other}")),
+    }
+}
+
+fn encode_body(bytes: &[u8]) -> (Option<String>, Option<String>) {
+    if bytes.is_empty() {
+        return (None, None);
+    }
+    let b64 = general_purpose::STANDARD.encode(bytes);
+    (Some(b64), Some("base64".to_string()))
+}
+
+/// Response headers in both shapes clients read them.
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ResponseHeaders {
+    /// Repeated names joined with ", ", as `Headers.get` returns them.
+    headers: HashMap<String, String>,
+    /// Every header as a `[name, value]` pair, repeats included, grouped by
+    /// name in order of first appearance.
+    raw_headers: Vec<(String, String)>,
+}
+
+/// Bytes as a string of chars U+0000 to U+00FF, the way `Headers` holds
+/// values, so nothing is lost to invalid UTF-8.
+fn byte_string(bytes: &[u8]) -> String {
+    bytes.iter().map(|&b| char::from(b)).collect()
+}
+
+/// The inverse of `byte_string`; `None` if a char is above U+00FF.
+fn byte_string_bytes(s: &str) -> Option<Vec<u8>> {
+    s.chars().map(|c| u8::try_from(c).ok()).collect()
+}
+
+fn request_header_map(headers: &Option<RequestHeaders>) -> Result<HeaderMap, String> {
+    let mut out = HeaderMap::new();
+    let pairs: Vec<(&String, &String)> = match headers {
+        None => Vec::new(),
+        Some(RequestHeaders::Map(map)) => map.iter().collect(),
+        Some(RequestHeaders::List(list)) => list.iter().map(|(k, v)| (k, v)).collect(),
+    };
+    for (k, v) in pairs {
+        let name = HeaderName::from_bytes(k.as_bytes())
+            .map_err(|e| format!("invalid header name {k}: {e}"))?;
+        let value = byte_string_bytes(v)
+            .ok_or_else(|| format!("invalid header value {k}: not a byte string"))?;
+        let value = HeaderValue::from_bytes(&value)
+            .map_err(|e| format!("invalid header value {k}: {e}"))?;
+        out.append(name, value);
+    }
+    Ok(out)
+}
+
+fn response_headers(headers: &HeaderMap) -> ResponseHeaders {
+    let mut out = ResponseHeaders::default();
+    for (k, v) in headers.iter() {
+        let value = byte_string(v.as_bytes());
+        out.headers
+            .entry(k.as_str().to_string())
+            .and_modify(|joined| {
+                joined.push_str(", ");
+                joined.push_str(&value);
+            })
+            .or_insert_with(|| value.clone());
+        out.raw_headers.push((k.as_str().to_string(), value));
+    }
+    out
+}
+
+/// Why a fetch failed. Timeouts and exceeded limits carry the code Node's
+/// HTTP clients use for them, so callers can tell them from network errors:
+/// `UND_ERR_CONNECT_TIMEOUT`, `ETIMEDOUT`, `ERR_FR_TOO_MANY_REDIRECTS`,
+/// `ERR_UNEXPECTED_REDIRECT` and `ERR_FR_MAX_BODY_LENGTH_EXCEEDED`. Egress
+/// policy rejections have `EGRESS_DENIED`.
+struct FetchFailure {
+    message: String,
+    code: Option<&'static str>,
+}
+
+impl FetchFailure {
+    fn new(code: &'static str, message: &str) -> Self {
+        Self {
+            message: message.to_string(),
+            code: Some(code),
+        }
+    }
+
+    fn body_too_large() -> Self {
+        Self::new("ERR_FR_MAX_BODY_LENGTH_EXCEEDED", "response body too large")
+    }
+}
+
+impl From<policy::EgressError> for FetchFailure {
+    fn from(e: policy::EgressError) -> Self {
+        Self {
+            message: e.to_string(),
+            code: e.code(),
+        }
+    }
+}
+
+impl From<String> for FetchFailure {
+    fn from(message: String) -> Self {
+        Self {
+            message,
+            code: None,
+        }
+    }
+}
+
+fn fetch_error(
+    id: u64,
+    status: u16,
+    headers: ResponseHeaders,
+    error: impl Into<FetchFailure>,
+) -> FetchResponse {
+    let error = error.into();
+    FetchResponse {
+        r#type: "fetch".to_string(),
+        id,
+        status,
+        headers,
+        body: None,
+        body_encoding: None,
+        error: Some(error.message),
+        code: error.code.map(String::from),
+    }
+}
+
+struct FetchTask {
+    abort: AbortHandle,
+    stream: bool,
+}
+
+type FetchTable = Arc<std::sync::Mutex<HashMap<u64, FetchTask>>>;
+
+/// Sends `msg` for fetch `id` unless it has been aborted. `last` retires the
+/// id, so a `fetch_abort` racing with completion produces no second reply.
+/// Body-carrying messages pass the budget `credit` reserved for them.
+fn emit_fetch<T: Serialize>(
+    fetches: &FetchTable,
+    out_tx: &Outbox,
+    id: u64,
+    msg: &T,
+    last: bool,
+    credit: Option<OwnedSemaphorePermit>,
+) -> bool {
+    let mut guard = fetches.lock().unwrap();
+    let active = if last {
+        guard.remove(&id).is_some()
+    } else {
+        guard.contains_key(&id)
+    };
+    if active {
+        let text = serde_json::to_string(msg).unwrap();
+        out_tx.send_with(Message::Text(text), credit);
+    }
+    active
+}
+
+/// Read-loop side of a streamed request body. Dropping the table's sender
+/// ends the body.
+type UploadFeed = mpsc::Sender<Vec<u8>>;
+
+type UploadTable = Arc<std::sync::Mutex<HashMap<u64, UploadFeed>>>;
+
+/// Wires a streamed request body to reqwest. The feed holds `queue_chunks`
+/// chunks, and each `fetch_body_chunk` is acknowledged once the HTTP client
+/// takes it, so a client that keeps at most that many unacknowledged never
+/// overflows the feed.
+fn start_upload(id: u64, out_tx: Outbox, queue_chunks: usize) -> (UploadFeed, reqwest::Body) {
+    let (feed_tx, feed_rx) = mpsc::channel::<Vec<u8>>(queue_chunks);
+    let body = futures_util::stream::unfold(feed_rx, move |mut rx| {
+        let out_tx = out_tx.clone();
+        async move {
+            let chunk = rx.recv().await?;
+            let ack = FetchBodyAckMessage {
+                r#type: "fetch_body_ack".to_string(),
+                id,
+                ok: true,
+                error: None,
+            };
+            send_reply(&out_tx, &ack).await;
+            Some((Ok::<_, std::io::Error>(chunk), rx))
+        }
+    });
+    (feed_tx, reqwest::Body::wrap_stream(body))
+}
+
+/// Per-session HTTP client. Redirects are followed by `send_fetch` rather
+/// than reqwest, so every hop passes the egress policy.
+#[derive(Clone)]
+struct FetchClient {
+    /// Clients by `ClientKey`, each with its own pins: a host approved for
+    /// one scheme and port may be denied for another.
+    clients: Arc<std::sync::Mutex<HashMap<ClientKey, PinnedClient>>>,
+    jars: cookies::Jars,
+    config: Arc<Config>,
+}
+
+/// A reqwest client that only connects where its resolver's pins allow.
+#[derive(Clone)]
+struct PinnedClient {
+    http: reqwest::Client,
+    resolver: Arc<PinnedResolver>,
+}
+
+/// A fetch's `(clientCert, cert, key, ca)`.
+type ClientOptions = (
+    Option<String>,
+    Option<String>,
+    Option<String>,
+    Option<String>,
+);
+
+/// The scheme and port of a hop, and the fetch's `ClientOptions`.
+type ClientKey = (String, u16, ClientOptions);
+
+const MAX_REDIRECTS: usize = 10;
+
+/// Clients kept per session; past this an arbitrary one is dropped, along
+/// with its idle connections.
+const MAX_CLIENTS: usize = 32;
+
+fn http_client(
+    resolver: Arc<PinnedResolver>,
+    ca: Option<&str>,
+    identity: Option<&tls::ClientIdentity>,
+    connect_timeout: Option<Duration>,
+) -> Result<reqwest::Client, String> {
+    // A system proxy would resolve hosts itself, out of the policy's sight.
+    let mut builder = reqwest::Client::builder()
+        .use_preconfigured_tls(tls::client_config(false, ca, identity, &[])?.0)
+        .redirect(reqwest::redirect::Policy::none())
+        .no_proxy()
+        .dns_resolver(resolver);
+    if let Some(timeout) = connect_timeout {
+        builder = builder.connect_timeout(timeout);
+    }
+    builder
+        .build()
+        .map_err(|e| format!("http client error: {e}"))
+}
+
+fn fetch_client(config: Arc<Config>) -> Result<FetchClient, String> {
+    Ok(FetchClient {
+        clients: Arc::default(),
+        jars: cookies::Jars::default(),
+        config,
+    })
+}
+
+impl FetchClient {
+    /// The client for hops to `scheme` and `port` with the settings `req`
+    /// asks for. A `connectTimeoutMs` is a setting of the whole client, so a
+    /// fetch with one gets a client of its own that is not kept.
+    fn pinned_for(
+        &self,
+        req: &FetchRequest,
+        scheme: &str,
+        port: u16,
+    ) -> Result<PinnedClient, String> {
+        let options = (
+            req.client_cert.clone(),
+            req.cert.clone(),
+            req.key.clone(),
+            req.ca.clone(),
+        );
+        let key = (scheme.to_string(), port, options);
+        let cached = req.connect_timeout_ms.is_none();
+        if let Some(client) = self.clients.lock().unwrap().get(&key).filter(|_| cached) {
+            return Ok(client.clone());
+        }
+        let identity = tls::identity(
+            &self.config.tls,
+            req.client_cert.as_deref(),
+            req.cert.as_deref(),
+            req.key.as_deref(),
+        )?;
+        let resolver = Arc::new(PinnedResolver::default());
+        let http = http_client(
+            resolver.clone(),
+            req.ca.as_deref(),
+            identity.as_ref(),
+            req.connect_timeout_ms.map(Duration::from_millis),
+        )?;
+        let client = PinnedClient { http, resolver };
+        if !cached {
+            return Ok(client);
+        }
+        let mut clients = self.clients.lock().unwrap();
+        if clients.len() >= MAX_CLIENTS {
+            if let Some(old) = clients.keys().next().cloned() {
+                clients.remove(&old);
+            }
+        }
+        clients.insert(key, client.clone());
+        Ok(client)
+    }
+}
+
+/// The jar a `cookie_*` message names; unlike on `fetch`, `false` is an error.
+fn cookie_jar(client: &FetchClient, name: &cookies::JarName) -> Result<cookies::Jar, String> {
+    client
+        .jars
+        .select(name)?
+        .ok_or_else(|| "no cookie jar selected".to_string())
+}
+
+/// Checks `url` against the egress policy and returns the client to send it
+/// with, its approved addresses pinned.
+async fn approve_url(
+    client: &FetchClient,
+    req: &FetchRequest,
+    url: &reqwest::Url,
+) -> Result<reqwest::Client, FetchFailure> {
+    let host = url
+        .host_str()
+        .ok_or_else(|| format!("invalid url: {url}"))?;
+    let port = url
+        .port_or_known_default()
+        .ok_or_else(|| format!("unsupported scheme: {}", url.scheme()))?;
+    let pinned = client.pinned_for(req, url.scheme(), port)?;
+    let addrs = policy::resolve(&client.config.egress, host, port, url.scheme()).await?;
+    pinned.resolver.pin(host, addrs);
+    Ok(pinned.http)
+}
+
+async fn send_fetch(
+    client: &FetchClient,
+    mut req: FetchRequest,
+) -> Result<reqwest::Response, FetchFailure> {
+    let method = req.method.clone().unwrap_or_else(|| "GET".to_string());
+    let mut method: reqwest::Method = method.parse().map_err(|e| format!("invalid method: {e}"))?;
+    let mut headers = request_header_map(&req.headers)?;
+    let mut url = reqwest::Url::parse(&req.url).map_err(|e| format!("invalid url: {e}"))?;
+    let jar = match &req.cookie_jar {
+        Some(name) => client.jars.select(name)?,
+        None => None,
+    };
+    let mut upload = req.upload.take();
+    let mut body = match upload {
+        Some(_) => Vec::new(),
+        None => decode_body(&req.body, &req.body_encoding)?,
+    };
+    let redirect = req.redirect.as_deref().unwrap_or("follow");
+    if !matches!(redirect, "follow" | "manual" | "error") {
+        return Err(format!("invalid redirect mode: {redirect}").into());
+    }
+
+    for _ in 0..=req.max_redirects.unwrap_or(MAX_REDIRECTS) {
+        let http = approve_url(client, &req, &url).await?;
+        let mut hop_headers = headers.clone();
+        if let Some(cookie) = jar.as_ref().and_then(|jar| jar.header(&url)) {
+            let mut values: Vec<&[u8]> = hop_headers
+                .get_all(reqwest::header::COOKIE)
+                .iter()
+                .map(HeaderValue::as_bytes)
+                .collect();
+            values.push(cookie.as_bytes());
+            let cookie = HeaderValue::from_bytes(&values.join(&b"; "[..]))
+                .map_err(|e| format!("invalid header value cookie: {e}"))?;
+            hop_headers.insert(reqwest::header::COOKIE, cookie);
+        }
+        let mut req_builder = http
+            .request(method.clone(), url.clone())
+            .headers(hop_headers);
+        if let Some(upload) = upload.take() {
+            req_builder = req_builder.body(upload);
+        } else if !body.is_empty() {
+            req_builder = req_builder.body(body.clone());
+        }
+        let resp = req_builder.send().await.map_err(|e| {
+            if e.is_connect() && e.is_timeout() {
+                FetchFailure::new("UND_ERR_CONNECT_TIMEOUT", "connect timeout")
+            } else {
+                FetchFailure::from(format!("fetch error: {e}"))
+            }
+        })?;
+        if let Some(jar) = &jar {
+            let set_cookies = resp.headers().get_all(reqwest::header::SET_COOKIE);
+            jar.store(&url, set_cookies.iter().filter_map(|v| v.to_str().ok()));
+        }
+
+        let status = resp.status();
+        let next = resp
+            .headers()
+            .get(reqwest::header::LOCATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|location| url.join(location).ok());
+        let Some(next) = next.filter(|_| status.is_redirection()) else {
+            return Ok(resp);
+        };
+        match redirect {
+            "manual" => return Ok(resp),
+            "error" if matches!(status.as_u16(), 301..=303 | 307 | 308) => {
+                return Err(FetchFailure::new(
+                    "ERR_UNEXPECTED_REDIRECT",
+                    "unexpected redirect",
+                ))
+            }
+            _ => {}
+        }
+        match status.as_u16() {
+            // Like browsers: 303 turns anything but HEAD into GET, 301/302 only POST.
+            301..=303 => {
+                let to_get = match status.as_u16() {
+                    303 => method != reqwest::Method::HEAD,
+                    _ => method == reqwest::Method::POST,
+                };
+                if to_get {
+                    method = reqwest::Method::GET;
+                    body.clear();
+                    for name in ["content-type", "content-length", "content-encoding"] {
+                        headers.remove(name);
+                    }
+                } else if req.body_stream.unwrap_or(false) {
+                    // Kept method, so the streamed body would have to be replayed.
+                    return Ok(resp);
+                }
+            }
+            // A streamed body was consumed by the first request and cannot
+            // be replayed; the client gets the redirect itself.
+            307 | 308 if req.body_stream.unwrap_or(false) => return Ok(resp),
+            307 | 308 => {}
+            _ => return Ok(resp),
+        }
+        if next.host_str() != url.host_str()
+            || next.port_or_known_default() != url.port_or_known_default()
+        {
+            for name in ["authorization", "cookie", "proxy-authorization"] {
+                headers.remove(name);
+            }
+        }
+        url = next;
+    }
+    Err(FetchFailure::new(
+        "ERR_FR_TOO_MANY_REDIRECTS",
+        "too many redirects",
+    ))
+}
+
+/// Runs `fut` unless the fetch's `timeoutMs` deadline passes first.
+async fn within<T>(
+    deadline: Option<tokio::time::Instant>,
+    fut: impl std::future::Future<Output = Result<T, FetchFailure>>,
+) -> Result<T, FetchFailure> {
+    let Some(deadline) = deadline else {
+        return fut.await;
+    };
+    tokio::time::timeout_at(deadline, fut)
+        .await
+        .unwrap_or_else(|_| Err(FetchFailure::new("ETIMEDOUT", "fetch timed out")))
+}
+
+fn fetch_deadline(req: &FetchRequest) -> Option<tokio::time::Instant> {
+    req.timeout_ms
+        .map(|ms| tokio::time::Instant::now() + Duration::from_millis(ms))
+}
+
+/// Whether a body of `len` bytes is over the request's `maxBodySize`.
+fn over_limit(limit: Option<usize>, len: u64) -> bool {
+    limit.is_some_and(|limit| len > limit as u64)
+}
+
+async fn read_body(resp: reqwest::Response, limit: Option<usize>) -> Result<Vec<u8>, FetchFailure> {
+    if over_limit(limit, resp.content_length().unwrap_or(0)) {
+        return Err(FetchFailure::body_too_large());
+    }
+    let mut body = Vec::new();
+    let mut chunks = resp.bytes_stream();
+    while let Some(chunk) = chunks.next().await {
+        let chunk = chunk.map_err(|e| format!("read body error: {e}"))?;
+        if over_limit(limit, (body.len() + chunk.len()) as u64) {
+            return Err(FetchFailure::body_too_large());
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body)
+}
+
+async fn perform_fetch(client: &FetchClient, req: FetchRequest) -> FetchResponse {
+    let id = req.id;
+    let deadline = fetch_deadline(&req);
+    let limit = req.max_body_size;
+    let resp = match within(deadline, send_fetch(client, req)).await {
+        Ok(r) => r,
+        Err(e) => return fetch_error(id, 0, ResponseHeaders::default(), e),
+    };
+
+    let status = resp.status().as_u16();
+    let headers_out = response_headers(resp.headers());
+    let bytes = match within(deadline, read_body(resp, limit)).await {
+        Ok(b) => b,
+        Err(e) => return fetch_error(id, status, headers_out, e),
+    };
+
+    let (body_out, body_encoding) = encode_body(&bytes);
+    FetchResponse {
+        r#type: "fetch".to_string(),
+        id,
+        status,
+        headers: headers_out,
+        body: body_out,
+        body_encoding,
+        error: None,
+        code: None,
+    }
+}
+
+fn fetch_end(id: u64, error: Option<FetchFailure>) -> FetchEndMessage {
+    let (error, code) = match error {
+        Some(e) => (Some(e.message), e.code.map(String::from)),
+        None => (None, None),
+    };
+    FetchEndMessage {
+        r#type: "fetch_end".to_string(),
+        id,
+        error,
+        code,
+    }
+}
+
+/// Streaming variant of `perform_fetch`: at most one `fetch_head`, then the
+/// body as `fetch_chunk` frames as they arrive, then exactly one `fetch_end`.
+async fn stream_fetch(client: FetchClient, req: FetchRequest, out_tx: Outbox, fetches: FetchTable) {
+    let id = req.id;
+    let deadline = fetch_deadline(&req);
+    let limit = req.max_body_size;
+    let resp = match within(deadline, send_fetch(&client, req)).await {
+        Ok(r) => r,
+        Err(e) => {
+            emit_fetch(&fetches, &out_tx, id, &fetch_end(id, Some(e)), true, None);
+            return;
+        }
+    };
+    if over_limit(limit, resp.content_length().unwrap_or(0)) {
+        let end = fetch_end(id, Some(FetchFailure::body_too_large()));
+        emit_fetch(&fetches, &out_tx, id, &end, true, None);
+        return;
+    }
+
+    let head = FetchHeadMessage {
+        r#type: "fetch_head".to_string(),
+        id,
+        status: resp.status().as_u16(),
+        headers: response_headers(resp.headers()),
+    };
+    if !emit_fetch(&fetches, &out_tx, id, &head, false, None) {
+        return;
+    }
+
+    // Ok(false) once the fetch was aborted and nothing more may be sent.
+    let pump = async {
+        let mut body = resp.bytes_stream();
+        let mut received = 0u64;
+        while let Some(chunk) = body.next().await {
+            let bytes = chunk.map_err(|e| format!("read body error: {e}"))?;
+            if bytes.is_empty() {
+                continue;
+            }
+            received += bytes.len() as u64;
+            if over_limit(limit, received) {
+                return Err(FetchFailure::body_too_large());
+            }
+            let msg = FetchChunkMessage {
+                r#type: "fetch_chunk".to_string(),
+                id,
+                data: general_purpose::STANDARD.encode(&bytes),
+                data_encoding: "base64".to_string(),
+            };
+            let credit = out_tx.reserve(msg.data.len()).await;
+            if !emit_fetch(&fetches, &out_tx, id, &msg, false, credit) {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    };
+    let error = match within(deadline, pump).await {
+        Ok(true) => None,
+        Ok(false) => return,
+        Err(e) => Some(e),
+    };
+
+    emit_fetch(&fetches, &out_tx, id, &fetch_end(id, error), true, None);
+}
+
+type WsSink = SplitSink<WebSocketStream<ServerStream>, Message>;
+
+/// Sends a session's frames to one WebSocket: first `backlog`, replayed after
+/// a resume, then the queue, until the queue closes, the socket fails or
+/// `stop` fires. The queue is handed back so a parked session keeps
+/// collecting frames for the next connection.
+fn spawn_writer(
+    mut ws_tx: WsSink,
+    mut out_rx: mpsc::UnboundedReceiver<Outgoing>,
+    replay: Arc<std::sync::Mutex<ReplayLog>>,
+    backlog: Vec<Message>,
+    stop: oneshot::Receiver<()>,
+) -> JoinHandle<mpsc::UnboundedReceiver<Outgoing>> {
+    tokio::spawn(async move {
+        let pump = async {
+            for msg in backlog {
+                if ws_tx.send(msg).await.is_err() {
+                    return;
+                }
+            }
+            // Each frame's budget credit is released as it is dropped here.
+            while let Some((msg, _credit)) = out_rx.recv().await {
+                replay.lock().unwrap().record(&msg);
+                if ws_tx.send(msg).await.is_err() {
+                    return;
+                }
+            }
+            let _ = ws_tx.close().await;
+        };
+        tokio::select! {
+            _ = stop => {}
+            _ = pump => {}
+        }
+        out_rx
+    })
+}
+
+/// Everything a session owns besides its WebSocket.
+struct SessionState {
+    out_tx: Outbox,
+    replay: Arc<std::sync::Mutex<ReplayLog>>,
+    client: FetchClient,
+    streams: StreamTable,
+    binary_frames: Arc<AtomicBool>,
+    fetches: FetchTable,
+    uploads: UploadTable,
+    next_stream_id: Arc<AtomicU64>,
+    listeners: HashMap<u64, AbortHandle>,
+    next_listener_id: u64,
+    udp_sockets: UdpTable,
+    /// Client frames handled so far, so a resuming client knows which of its
+    /// own messages to send again.
+    received: u64,
+}
+
+fn new_session(
+    config: &Arc<Config>,
+    replay_bytes: usize,
+) -> Result<(SessionState, mpsc::UnboundedReceiver<Outgoing>), String> {
+    let (out_tx, out_rx) = Outbox::new(config.limits.outbound_queue_bytes);
+    let state = SessionState {
+        out_tx,
+        replay: Arc::new(std::sync::Mutex::new(ReplayLog::new(replay_bytes))),
+        client: fetch_client(config.clone())?,
+        streams: Arc::new(Mutex::new(HashMap::new())),
+        binary_frames: Arc::new(AtomicBool::new(false)),
+        fetches: Arc::new(std::sync::Mutex::new(HashMap::new())),
+        uploads: Arc::new(std::sync::Mutex::new(HashMap::new())),
+        next_stream_id: Arc::new(AtomicU64::new(1)),
+        listeners: HashMap::new(),
+        next_listener_id: 1,
+        udp_sockets: Arc::new(std::sync::Mutex::new(HashMap::new())),
+        received: 0,
+    };
+    Ok((state, out_rx))
+}
+
+/// Releases everything the session still holds. Readers stop and send their
+/// last close messages, which a writer still attached flushes.
+async fn close_session(state: SessionState) {
+    for abort in state.listeners.values() {
+        abort.abort();
+    }
+    for (_, entry) in state.streams.lock().await.drain() {
+        let _ = entry.close.send(ReaderStop::Close("session"));
+    }
+    for (_, entry) in state.udp_sockets.lock().unwrap().drain() {
+        let _ = entry.close.send("session");
+    }
+    for (_, task) in state.fetches.lock().unwrap().drain() {
+        task.abort.abort();
+    }
+    state.uploads.lock().unwrap().clear();
+}
+
+/// A session between connections, with the frames queued for the next one.
+struct DetachedSession {
+    state: SessionState,
+    out_rx: mpsc::UnboundedReceiver<Outgoing>,
+}
+
+/// Where the registry finds a resumable session.
+enum SessionSlot {
+    /// Attached to a connection, which hands the session over when sent a
+    /// channel here. A client may reconnect before the proxy has noticed its
+    /// old connection died.
+    Live(oneshot::Sender<oneshot::Sender<DetachedSession>>),
+    /// Its WebSocket went away. Sockets stay open and their output queues up
+    /// (within `outbound_queue_bytes`) until a client reattaches or `expiry`
+    /// closes it.
+    Parked {
+        session: Box<DetachedSession>,
+        expiry: AbortHandle,
+    },
+}
+
+/// Resumable sessions by token, shared by every connection.
+type SessionRegistry = Arc<std::sync::Mutex<HashMap<String, SessionSlot>>>;
+
+fn park_session(
+    sessions: &mut HashMap<String, SessionSlot>,
+    registry: &SessionRegistry,
+    token: String,
+    session: DetachedSession,
+    timeout: Duration,
+) {
+    let registry = registry.clone();
+    let key = token.clone();
+    // The caller holds the registry lock, so the slot is in before this looks.
+    let expiry = tokio::spawn(async move {
+        tokio::time::sleep(timeout).await;
+        let expired = {
+            let mut guard = registry.lock().unwrap();
+            match guard.remove(&key) {
+                Some(SessionSlot::Parked { session, .. }) => Some(session),
+                // Resumed just before the timer was aborted.
+                Some(live) => {
+                    guard.insert(key, live);
+                    None
+                }
+                None => None,
+            }
+        };
+        if let Some(session) = expired {
+            log::debug!("resumable session expired");
+            close_session(session.state).await;
+        }
+    });
+    let slot = SessionSlot::Parked {
+        session: Box::new(session),
+        expiry: expiry.abort_handle(),
+    };
+    sessions.insert(token, slot);
+}
+
+async fn take_session(sessions: &SessionRegistry, token: &str) -> Option<DetachedSession> {
+    let receiver = {
+        let mut guard = sessions.lock().unwrap();
+        match guard.remove(token)? {
+            SessionSlot::Parked { session, expiry } => {
+                expiry.abort();
+                return Some(*session);
+            }
+            SessionSlot::Live(takeover) => {
+                let (tx, rx) = oneshot::channel();
+                takeover.send(tx).ok()?;
+                rx
+            }
+        }
+    };
+    receiver.await.ok()
+}
+
+fn text_message<T: Serialize>(msg: &T) -> Message {
+    Message::Text(serde_json::to_string(msg).unwrap())
+}
+
+/// Waits for the `auth` message an unauthenticated session must send first.
+async fn read_auth<S>(ws_rx: &mut S) -> Option<AuthRequest>
+where
+    S: Stream<Item = Result<Message, WsError>> + Unpin,
+{
+    loop {
+        let text = match ws_rx.next().await? {
+            Ok(Message::Text(text)) => text,
+            Ok(Message::Ping(_)) | Ok(Message::Pong(_)) => continue,
+            _ => return None,
+        };
+        let value: serde_json::Value = serde_json::from_str(&text).ok()?;
+        if value.get("type").and_then(|v| v.as_str()) != Some("auth") {
+            return None;
+        }
+        return serde_json::from_value(value).ok();
+    }
+}
+
+async fn handle_session(
+    stream: ServerStream,
+    peer: SocketAddr,
+    config: Arc<Config>,
+    sessions: SessionRegistry,
+) {
+    let ws_config = WebSocketConfig {
+        max_message_size: Some(config.limits.max_message_size),
+        max_frame_size: Some(config.limits.max_frame_size),
+        ..WebSocketConfig::default()
+    };
+    let expected_token = config.auth.token.clone().filter(|_| config.auth.enabled);
+    let mut authenticated = expected_token.is_none();
+    let mut session_request = None;
+    // The error type is fixed by tungstenite's handshake callback.
+    #[allow(clippy::result_large_err)]
+    let check_request = |req: &Request, resp: Response| {
+        let origin = req
+            .headers()
+            .get("origin")
+            .map(|v| v.to_str().unwrap_or(""));
+        let origin_ok = match origin {
+            Some(origin) => origin_allowed(origin, &config.auth.allowed_origins),
+            None => config.auth.allow_missing_origin,
+        };
+        if !origin_ok {
+            log::warn!(
+                "Rejected origin {:?} from {peer}",
+                origin.unwrap_or("<none>")
+            );
+            let mut err = HandshakeRejection::new(Some("origin not allowed".to_string()));
+            *err.status_mut() = StatusCode::FORBIDDEN;
+            return Err(err);
+        }
+        if let (Some(expected), Some(presented)) = (&expected_token, request_token(req)) {
+            authenticated = token_matches(&presented, expected);
+        }
+        if config.features.resume {
+            session_request = session::request_session(req);
+        }
+        Ok(resp)
+    };
+    let ws_stream = match accept_hdr_async_with_config(stream, check_request, Some(ws_config)).await
+    {
+        Ok(ws) => ws,
+        Err(e) => {
+            log::warn!("WS accept error from {peer}: {e}");
+            return;
+        }
+    };
+    log::debug!("session opened from {peer}");
+
+    let (mut ws_tx, mut ws_rx) = ws_stream.split();
+    let _ = ws_tx
+        .send(text_message(&server_hello(&config, !authenticated)))
+        .await;
+
+    if !authenticated {
+        let expected = expected_token.as_deref().unwrap_or_default();
+        match tokio::time::timeout(AUTH_TIMEOUT, read_auth(&mut ws_rx)).await {
+            Ok(Some(req)) if token_matches(&req.token, expected) => {
+                let resp = AuthResponse {
+                    r#type: "auth".to_string(),
+                    id: req.id,
+                    ok: true,
+                };
+                let _ = ws_tx.send(text_message(&resp)).await;
+            }
+            _ => {
+                log::warn!("Rejected unauthenticated session from {peer}");
+                let frame = CloseFrame {
+                    code: CloseCode::from(CLOSE_UNAUTHORIZED),
+                    reason: "unauthorized".into(),
+                };
+                let _ = ws_tx.send(Message::Close(Some(frame))).await;
+                return;
+            }
+        }
+    }
+
+    // Reattach to a parked session if asked to and it can still replay
+    // everything the client missed; otherwise start afresh.
+    let mut resumed = None;
+    let mut resume_error = None;
+    if let Some(SessionRequest::Resume { token, received }) = &session_request {
+        match take_session(&sessions, token).await {
+            Some(detached) => {
+                let backlog = detached.state.replay.lock().unwrap().resume(*received);
+                match backlog {
+                    Some(backlog) => resumed = Some((token.clone(), detached, backlog)),
+                    None => {
+                        resume_error = Some("replay window exceeded".to_string());
+                        close_session(detached.state).await;
+                    }
+                }
+            }
+            None => resume_error = Some("unknown session".to_string()),
+        }
+    }
+    let resumable = session_request.is_some();
+    let was_resumed = resumed.is_some();
+    let (token, state, out_rx, backlog) = match resumed {
+        Some((token, detached, backlog)) => (Some(token), detached.state, detached.out_rx, backlog),
+        None => {
+            let token = match resumable.then(auth::generate_token).transpose() {
+                Ok(token) => token,
+                Err(e) => {
+                    log::warn!("{e}");
+                    return;
+                }
+            };
+            let replay_bytes = if resumable {
+                config.limits.resume_buffer_bytes
+            } else {
+                0
+            };
+            match new_session(&config, replay_bytes) {
+                Ok((state, out_rx)) => (token, state, out_rx, Vec::new()),
                 Err(e) => {
-                    eprintln!("WS accept error: {e}");
+                    log::warn!("{e}");
                     return;
                 }
+            }
+        }
+    };
+    if let Some(token) = &token {
+        let msg = SessionMessage {
+            r#type: "session".to_string(),
+            token: token.clone(),
+            resumed: was_resumed,
+            received: state.received,
+            error: resume_error,
+        };
+        let _ = ws_tx.send(text_message(&msg)).await;
+    }
+
+    let SessionState {
+        out_tx,
+        replay,
+        client,
+        streams,
+        binary_frames,
+        fetches,
+        uploads,
+        next_stream_id,
+        mut listeners,
+        mut next_listener_id,
+        udp_sockets,
+        mut received,
+    } = state;
+    let (stop, stop_rx) = oneshot::channel();
+    let writer = spawn_writer(ws_tx, out_rx, replay.clone(), backlog, stop_rx);
+    let (kick, mut takeover) = oneshot::channel();
+    if let Some(token) = &token {
+        let slot = SessionSlot::Live(kick);
+        sessions.lock().unwrap().insert(token.clone(), slot);
+    }
+    // A normal close (1000) ends a resumable session; anything else parks it.
+    let mut ended = false;
+    let mut handover = None;
+
+    loop {
+        let msg = tokio::select! {
+            msg = ws_rx.next() => match msg {
+                Some(msg) => msg,
+                None => break,
+            },
+            // Never ready for sessions that are not in the registry.
+            Ok(tx) = &mut takeover => {
+                log::debug!("session from {peer} resumed elsewhere");
+                handover = Some(tx);
+                break;
+            }
+        };
+        if matches!(msg, Ok(Message::Text(_) | Message::Binary(_))) {
+            received += 1;
+        }
+        let msg = match msg {
+            Ok(Message::Text(text)) => text,
+            Ok(Message::Binary(bin)) => match decode_frame(&bin) {
+                Some(frame) if frame.kind == FRAME_TCP_WRITE => {
+                    let data = frame.payload.to_vec();
+                    if let Err(e) = write_stream(&streams, frame.stream_id, frame.id, data).await {
+                        send_reply(&out_tx, &tcp_write_response(frame.id, Err(e))).await;
+                    }
+                    continue;
+                }
+                Some(frame) => {
+                    log::warn!("Unexpected binary frame kind: {}", frame.kind);
+                    continue;
+                }
+                None => String::from_utf8_lossy(&bin).to_string(),
+            },
+            Ok(Message::Close(frame)) => {
+                ended = frame.is_some_and(|f| f.code == CloseCode::Normal);
+                break;
+            }
+            Ok(_) => continue,
+            Err(e) => {
+                log::warn!("WS recv error: {e}");
+                break;
+            }
+        };
+
+        let value: serde_json::Value = match serde_json::from_str(&msg) {
+            Ok(v) => v,
+            Err(e) => {
+                log::warn!("Bad JSON: {e}");
+                continue;
+            }
+        };
+
+        let msg_type = value.get("type").and_then(|v| v.as_str()).unwrap_or("");
+
+        if !message_enabled(&config.features, msg_type) {
+            let resp = ErrorResponse {
+                r#type: msg_type.to_string(),
+                id: value.get("id").and_then(|v| v.as_u64()).unwrap_or(0),
+                ok: false,
+                error: format!("{msg_type} is disabled on this proxy"),
+            };
+            send_reply(&out_tx, &resp).await;
+            continue;
+        }
+
+        if msg_type == "auth" {
+            // Already authenticated, either in the handshake or by an earlier `auth`.
+            let resp = AuthResponse {
+                r#type: "auth".to_string(),
+                id: value.get("id").and_then(|v| v.as_u64()).unwrap_or(0),
+                ok: true,
+            };
+            send_reply(&out_tx, &resp).await;
+            continue;
+        }
+
+        if msg_type == "hello" {
+            let req: HelloRequest = match serde_json::from_value(value) {
+                Ok(v) => v,
+                Err(e) => {
+                    log::warn!("Bad hello payload: {e}");
+                    continue;
+                }
+            };
+
+            if let Some(version) = &req.protocol_version {
+                if version.split('.').next() != PROTOCOL_VERSION.split('.').next() {
+                    log::warn!("Client speaks protocol {version}, proxy {PROTOCOL_VERSION}");
+                }
+            }
+
+            // Unknown features are dropped so newer clients degrade gracefully.
+            let features: Vec<String> = req
+                .features
+                .unwrap_or_default()
+                .into_iter()
+                .filter(|f| f == "binaryFrames" && config.features.binary_frames)
+                .collect();
+            binary_frames.store(
+                features.iter().any(|f| f == "binaryFrames"),
+                Ordering::Relaxed,
+            );
+
+            let resp = HelloResponse {
+                r#type: "hello".to_string(),
+                id: req.id,
+                ok: true,
+                protocol_version: PROTOCOL_VERSION.to_string(),
+                features,
+            };
+            send_reply(&out_tx, &resp).await;
+            continue;
+        }
+
+        if msg_type == "fetch" {
+            let mut req: FetchRequest = match serde_json::from_value(value) {
+                Ok(v) => v,
+                Err(e) => {
+                    log::warn!("Bad fetch payload: {e}");
+                    continue;
+                }
+            };
+
+            let id = req.id;
+            let refused = {
+                let guard = fetches.lock().unwrap();
+                if guard.contains_key(&id) {
+                    Some("duplicate fetch id")
+                } else if guard.len() >= config.limits.max_fetches {
+                    Some("too many fetches")
+                } else {
+                    None
+                }
+            };
+            if let Some(error) = refused {
+                let resp = fetch_error(id, 0, ResponseHeaders::default(), error.to_string());
+                send_reply(&out_tx, &resp).await;
+                continue;
+            }
+
+            let mut feed = None;
+            if req.body_stream.unwrap_or(false) {
+                let (upload, body) =
+                    start_upload(id, out_tx.clone(), config.limits.upload_queue_chunks);
+                feed = Some(upload.downgrade());
+                uploads.lock().unwrap().insert(id, upload);
+                req.upload = Some(body);
+            }
+
+            let stream = req.stream.unwrap_or(false);
+            let client = client.clone();
+            let out_tx_fetch = out_tx.clone();
+            let fetches_task = fetches.clone();
+            let uploads_task = uploads.clone();
+            // Held until the task is in the table, which it leaves when done.
+            let mut guard = fetches.lock().unwrap();
+            let handle = tokio::spawn(async move {
+                // A fetch can finish before the client ends its body (an
+                // error, a redirect returned as-is, an early response); its
+                // feed goes then, unless the id already carries a new upload.
+                // Only a weak handle is kept, so ending the body is up to the table.
+                let retire_feed = || {
+                    let Some(feed) = feed.as_ref().and_then(|f| f.upgrade()) else {
+                        return;
+                    };
+                    let mut uploads = uploads_task.lock().unwrap();
+                    if uploads.get(&id).is_some_and(|f| f.same_channel(&feed)) {
+                        uploads.remove(&id);
+                    }
+                };
+                if stream {
+                    stream_fetch(client, req, out_tx_fetch, fetches_task).await;
+                    retire_feed();
+                } else {
+                    let resp = perform_fetch(&client, req).await;
+                    retire_feed();
+                    let size = resp.body.as_ref().map_or(0, String::len);
+                    let credit = out_tx_fetch.reserve(size).await;
+                    emit_fetch(&fetches_task, &out_tx_fetch, id, &resp, true, credit);
+                }
+            });
+            guard.insert(
+                id,
+                FetchTask {
+                    abort: handle.abort_handle(),
+                    stream,
+                },
+            );
+            continue;
+        }
+
+        if msg_type == "fetch_abort" {
+            let req: FetchAbortRequest = match serde_json::from_value(value) {
+                Ok(v) => v,
+                Err(e) => {
+                    log::warn!("Bad fetch_abort payload: {e}");
+                    continue;
+                }
+            };
+
+            uploads.lock().unwrap().remove(&req.id);
+            let task = fetches.lock().unwrap().remove(&req.id);
+            if let Some(task) = task {
+                task.abort.abort();
+                if task.stream {
+                    let msg = fetch_end(req.id, Some("aborted".to_string().into()));
+                    send_reply(&out_tx, &msg).await;
+                } else {
+                    let resp =
+                        fetch_error(req.id, 0, ResponseHeaders::default(), "aborted".to_string());
+                    send_reply(&out_tx, &resp).await;
+                }
+            }
+            continue;
+        }
+
+        if msg_type == "fetch_body_chunk" {
+            let req: FetchBodyChunkRequest = match serde_json::from_value(value) {
+                Ok(v) => v,
+                Err(e) => {
+                    log::warn!("Bad fetch_body_chunk payload: {e}");
+                    continue;
+                }
+            };
+
+            let error = match decode_body(&req.data, &req.data_encoding) {
+                Ok(chunk) => match uploads
+                    .lock()
+                    .unwrap()
+                    .get(&req.id)
+                    .map(|f| f.try_send(chunk))
+                {
+                    Some(Ok(())) => continue,
+                    Some(Err(mpsc::error::TrySendError::Full(_))) => {
+                        Some("upload window exceeded".to_string())
+                    }
+                    Some(Err(mpsc::error::TrySendError::Closed(_))) => {
+                        Some("upload closed".to_string())
+                    }
+                    None => Some("unknown upload".to_string()),
+                },
+                Err(e) => Some(e),
+            };
+            let ack = FetchBodyAckMessage {
+                r#type: "fetch_body_ack".to_string(),
+                id: req.id,
+                ok: false,
+                error,
+            };
+            send_reply(&out_tx, &ack).await;
+            continue;
+        }
+
+        if msg_type == "fetch_body_end" {
+            let req: FetchBodyEndRequest = match serde_json::from_value(value) {
+                Ok(v) => v,
+                Err(e) => {
+                    log::warn!("Bad fetch_body_end payload: {e}");
+                    continue;
+                }
+            };
+
+            // Dropping the feed ends the body once its queued chunks are sent.
+            uploads.lock().unwrap().remove(&req.id);
+            continue;
+        }
+
+        if msg_type == "tcp_open" {
+            let req: TcpOpenRequest = match serde_json::from_value(value) {
+                Ok(v) => v,
+                Err(e) => {
+                    log::warn!("Bad tcp_open payload: {e}");
+                    continue;
+                }
+            };
+
+            if streams.lock().await.len() >= config.limits.max_streams {
+                let resp = TcpOpenResponse {
+                    r#type: "tcp_open".to_string(),
+                    id: req.id,
+                    stream_id: None,
+                    ok: false,
+                    error: Some("too many streams".to_string()),
+                    code: None,
+                    tls: None,
+                };
+                send_reply(&out_tx, &resp).await;
+                continue;
+            }
+
+            let use_tls = req.tls.unwrap_or(false);
+            let scheme = if use_tls { "tls" } else { "tcp" };
+            let stream = match policy::resolve(&config.egress, &req.host, req.port, scheme).await {
+                Ok(addrs) => TcpStream::connect(&addrs[..])
+                    .await
+                    .map_err(|e| (format!("connect error: {e}"), None)),
+                Err(e) => Err((e.to_string(), e.code())),
+            };
+            let stream = match stream {
+                Ok(s) => s,
+                Err((e, code)) => {
+                    let resp = TcpOpenResponse {
+                        r#type: "tcp_open".to_string(),
+                        id: req.id,
+                        stream_id: None,
+                        ok: false,
+                        error: Some(e),
+                        code: code.map(str::to_string),
+                        tls: None,
+                    };
+                    send_reply(&out_tx, &resp).await;
+                    continue;
+                }
             };
 
-            let (mut ws_tx, mut ws_rx) = ws_stream.split();
-            let client = reqwest::Client::new();
-
-            let (out_tx, mut out_rx) = mpsc::unbounded_channel::<String>();
-            let out_tx_clone = out_tx.clone();
-
-            let writer = tokio::spawn(async move {
-                while let Some(msg) = out_rx.recv().await {
-                    if ws_tx.send(Message::Text(msg)).await.is_err() {
-                        break;
+            let (reader, writer, tls_info) = if use_tls {
+                let connected = match TlsClient::new(&config, &req.host, &req.options) {
+                    Ok(client) => client.connect(stream).await,
+                    Err(e) => Err(e),
+                };
+                match connected {
+                    Ok((tls_stream, info)) => {
+                        let (reader, writer) = tokio::io::split(tls_stream);
+                        (
+                            StreamReader::Tls(reader),
+                            StreamWriter::Tls(writer),
+                            Some(info),
+                        )
+                    }
+                    Err(e) => {
+                        let resp = TcpOpenResponse {
+                            r#type: "tcp_open".to_string(),
+                            id: req.id,
+                            stream_id: None,
+                            ok: false,
+                            error: Some(e),
+                            code: None,
+                            tls: None,
+                        };
+                        send_reply(&out_tx, &resp).await;
+                        continue;
                     }
                 }
-            });
+            } else {
+                let (reader, writer) = stream.into_split();
+                (
+                    StreamReader::Plain(reader),
+                    StreamWriter::Plain(writer),
+                    None,
+                )
+            };
 
-            let streams: Arc<Mutex<HashMap<u64, StreamWriter>>> =
-                Arc::new(Mutex::new(HashMap::new()));
-            let mut next_stream_id: u64 = 1;
+            let stream_id = next_stream_id.fetch_add(1, Ordering::Relaxed);
+            let (entry, paused) = stream_entry(writer, req.host.clone(), out_tx.clone());
+            streams.lock().await.insert(stream_id, entry);
+            spawn_reader(
+                reader,
+                stream_id,
+                out_tx.clone(),
+                streams.clone(),
+                binary_frames.clone(),
+                config.limits.tcp_read_chunk,
+                paused,
+            );
 
-            while let Some(msg) = ws_rx.next().await {
-                let msg = match msg {
-                    Ok(Message::Text(text)) => text,
-                    Ok(Message::Binary(bin)) => String::from_utf8_lossy(&bin).to_string(),
-                    Ok(Message::Close(_)) => break,
-                    Ok(_) => continue,
-                    Err(e) => {
-                        eprintln!("WS recv error: {e}");
-                        break;
+            let resp = TcpOpenResponse {
+                r#type: "tcp_open".to_string(),
+                id: req.id,
+                stream_id: Some(stream_id),
+                ok: true,
+                error: None,
+                code: None,
+                tls: tls_info,
+            };
+            send_reply(&out_tx, &resp).await;
+            continue;
+        }
+
+        if msg_type == "tcp_starttls" {
+            let req: TcpStartTlsRequest = match serde_json::from_value(value) {
+                Ok(v) => v,
+                Err(e) => {
+                    log::warn!("Bad tcp_starttls payload: {e}");
+                    continue;
+                }
+            };
+            // The task owns the stream, out of the table, until the handshake
+            // is over; this loop meanwhile goes on with other requests.
+            let config = config.clone();
+            let streams = streams.clone();
+            let out_tx = out_tx.clone();
+            let binary_frames = binary_frames.clone();
+            tokio::spawn(async move {
+                let result = start_tls(&config, &streams, req.stream_id, &req.options).await;
+                let (reader, writer, host, was_paused, info) = match result {
+                    Ok(upgraded) => upgraded,
+                    Err((e, closed)) => {
+                        let resp = TcpStartTlsResponse {
+                            r#type: "tcp_starttls".to_string(),
+                            id: req.id,
+                            ok: false,
+                            error: Some(e.clone()),
+                            tls: None,
+                        };
+                        send_reply(&out_tx, &resp).await;
+                        if closed {
+                            let msg = TcpCloseMessage {
+                                r#type: "tcp_close".to_string(),
+                                stream_id: req.stream_id,
+                                reason: "error".to_string(),
+                                error: Some(e),
+                            };
+                            send_json(&out_tx, &msg);
+                        }
+                        return;
                     }
                 };
 
-                let value: serde_json::Value = match serde_json::from_str(&msg) {
-                    Ok(v) => v,
-                    Err(e) => {
-                        eprintln!("Bad JSON: {e}");
-                        continue;
-                    }
+                // Back in the table before the reply, so writes that follow it
+                // find the stream; the reader only starts after, so no
+                // `tcp_data` precedes it.
+                let (entry, paused) = stream_entry(writer, host, out_tx.clone());
+                entry.paused.send_replace(was_paused);
+                streams.lock().await.insert(req.stream_id, entry);
+                let resp = TcpStartTlsResponse {
+                    r#type: "tcp_starttls".to_string(),
+                    id: req.id,
+                    ok: true,
+                    error: None,
+                    tls: Some(info),
                 };
+                send_reply(&out_tx, &resp).await;
+                spawn_reader(
+                    reader,
+                    req.stream_id,
+                    out_tx,
+                    streams,
+                    binary_frames,
+                    config.limits.tcp_read_chunk,
+                    paused,
+                );
+            });
+            continue;
+        }
 
-                let msg_type = value.get("type").and_then(|v| v.as_str()).unwrap_or("");
+        if msg_type == "tcp_write" {
+            let req: TcpWriteRequest = match serde_json::from_value(value) {
+                Ok(v) => v,
+                Err(e) => {
+                    log::warn!("Bad tcp_write payload: {e}");
+                    continue;
+                }
+            };
 
-                if msg_type == "fetch" {
-                    let req: FetchRequest = match serde_json::from_value(value) {
-                        Ok(v) => v,
-                        Err(e) => {
-                            eprintln!("Bad fetch payload: {e}");
-                            continue;
-                        }
-                    };
+            let result = match decode_body(&req.data, &req.data_encoding) {
+                Ok(data) => write_stream(&streams, req.stream_id, req.id, data).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                send_reply(&out_tx, &tcp_write_response(req.id, Err(e))).await;
+            }
+            continue;
+        }
 
-                    let method = req.method.clone().unwrap_or_else(|| "GET".to_string());
-                    let method = match method.parse() {
-                        Ok(m) => m,
-                        Err(e) => {
-                            let resp = FetchResponse {
-                                r#type: "fetch".to_string(),
-                                id: req.id,
-                                status: 0,
-                                headers: HashMap::new(),
-                                body: None,
-                                body_encoding: None,
-                                error: Some(format!("invalid method: {e}")),
-                            };
-                            let _ = out_tx_clone.send(serde_json::to_string(&resp).unwrap());
-                            continue;
-                        }
-                    };
+        if msg_type == "tcp_end" {
+            let req: TcpEndRequest = match serde_json::from_value(value) {
+                Ok(v) => v,
+                Err(e) => {
+                    log::warn!("Bad tcp_end payload: {e}");
+                    continue;
+                }
+            };
 
-                    let headers = match header_map_from_hash(&req.headers) {
-                        Ok(h) => h,
-                        Err(e) => {
-                            let resp = FetchResponse {
-                                r#type: "fetch".to_string(),
-                                id: req.id,
-                                status: 0,
-                                headers: HashMap::new(),
-                                body: None,
-                                body_encoding: None,
-                                error: Some(e),
-                            };
-                            let _ = out_tx_clone.send(serde_json::to_string(&resp).unwrap());
-                            continue;
-                        }
-                    };
+            if let Err(e) = end_stream(&streams, req.stream_id, req.id).await {
+                send_reply(&out_tx, &tcp_end_response(req.id, Err(e))).await;
+            }
+            continue;
+        }
 
-                    let body = match decode_body(&req.body, &req.body_encoding) {
-                        Ok(b) => b,
-                        Err(e) => {
-                            let resp = FetchResponse {
-                                r#type: "fetch".to_string(),
-                                id: req.id,
-                                status: 0,
-                                headers: HashMap::new(),
-                                body: None,
-                                body_encoding: None,
-                                error: Some(e),
-                            };
-                            let _ = out_tx_clone.send(serde_json::to_string(&resp).unwrap());
-                            continue;
-                        }
-                    };
+        if msg_type == "tcp_pause" || msg_type == "tcp_resume" {
+            let pause = msg_type == "tcp_pause";
+            let req: TcpFlowRequest = match serde_json::from_value(value) {
+                Ok(v) => v,
+                Err(e) => {
+                    log::warn!("Bad tcp_pause/tcp_resume payload: {e}");
+                    continue;
+                }
+            };
 
-                    let mut req_builder = client.request(method, req.url).headers(headers);
-                    if !body.is_empty() {
-                        req_builder = req_builder.body(body);
-                    }
+            if let Some(entry) = streams.lock().await.get(&req.stream_id) {
+                entry.paused.send_replace(pause);
+            }
+            continue;
+        }
 
-                    let resp = match req_builder.send().await {
-                        Ok(r) => r,
-                        Err(e) => {
-                            let resp = FetchResponse {
-                                r#type: "fetch".to_string(),
-                                id: req.id,
-                                status: 0,
-                                headers: HashMap::new(),
-                                body: None,
-                                body_encoding: None,
-                                error: Some(format!("fetch error: {e}")),
-                            };
-                            let _ = out_tx_clone.send(serde_json::to_string(&resp).unwrap());
-                            continue;
-                        }
-                    };
+        if msg_type == "tcp_listen" {
+            let req: TcpListenRequest = match serde_json::from_value(value) {
+                Ok(v) => v,
+                Err(e) => {
+                    log::warn!("Bad tcp_listen payload: {e}");
+                    continue;
+                }
+            };
 
-                    let status = resp.status().as_u16();
-                    let headers_out = headers_to_hash(resp.headers());
-                    let bytes = match resp.bytes().await {
-                        Ok(b) => b.to_vec(),
-                        Err(e) => {
-                            let resp = FetchResponse {
-                                r#type: "fetch".to_string(),
-                                id: req.id,
-                                status,
-                                headers: headers_out,
-                                body: None,
-                                body_encoding: None,
-                                error: Some(format!("read body error: {e}")),
-                            };
-                            let _ = out_tx_clone.send(serde_json::to_string(&resp).unwrap());
-                            continue;
-                        }
-                    };
+            let host = req
+                .host
+                .clone()
+                .unwrap_or_else(|| config.inbound.default_host.clone());
+            if !config
+                .inbound
+                .allowed_hosts
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(&host))
+            {
+                let resp = tcp_listen_error(req.id, format!("listen address not allowed: {host}"));
+                send_reply(&out_tx, &resp).await;
+                continue;
+            }
+            if listeners.len() >= config.limits.max_listeners {
+                let resp = tcp_listen_error(req.id, "too many listeners".to_string());
+                send_reply(&out_tx, &resp).await;
+                continue;
+            }
 
-                    let (body_out, body_encoding) = encode_body(&bytes);
-                    let resp = FetchResponse {
-                        r#type: "fetch".to_string(),
-                        id: req.id,
-                        status,
-                        headers: headers_out,
-                        body: body_out,
-                        body_encoding,
-                        error: None,
-                    };
+            let listener = match bind_listener(&host, req.port, req.backlog).await {
+                Ok(l) => l,
+                Err(e) => {
+                    send_reply(&out_tx, &tcp_listen_error(req.id, e)).await;
+                    continue;
+                }
+            };
+            let local = listener.local_addr().ok();
+            let listener_id = next_listener_id;
+            next_listener_id += 1;
+            let abort = spawn_acceptor(
+                listener,
+                listener_id,
+                out_tx.clone(),
+                streams.clone(),
+                next_stream_id.clone(),
+                binary_frames.clone(),
+                config.clone(),
+            );
+            listeners.insert(listener_id, abort);
+
+            let resp = TcpListenResponse {
+                r#type: "tcp_listen".to_string(),
+                id: req.id,
+                listener_id: Some(listener_id),
+                ok: true,
+                local_address: local.map(|addr| addr.ip().to_string()),
+                local_port: local.map(|addr| addr.port()),
+                error: None,
+            };
+            send_reply(&out_tx, &resp).await;
+            continue;
+        }
 
-                    let _ = out_tx_clone.send(serde_json::to_string(&resp).unwrap());
+        if msg_type == "tcp_unlisten" {
+            let req: TcpUnlistenRequest = match serde_json::from_value(value) {
+                Ok(v) => v,
+                Err(e) => {
+                    log::warn!("Bad tcp_unlisten payload: {e}");
                     continue;
                 }
+            };
 
-                if msg_type == "tcp_open" {
-                    let req: TcpOpenRequest = match serde_json::from_value(value) {
-                        Ok(v) => v,
-                        Err(e) => {
-                            eprintln!("Bad tcp_open payload: {e}");
-                            continue;
-                        }
-                    };
+            // Connections already accepted stay open, as with Node's `server.close`.
+            let error = match listeners.remove(&req.listener_id) {
+                Some(abort) => {
+                    abort.abort();
+                    None
+                }
+                None => Some("unknown listener".to_string()),
+            };
+            let resp = TcpUnlistenResponse {
+                r#type: "tcp_unlisten".to_string(),
+                id: req.id,
+                ok: error.is_none(),
+                error,
+            };
+            send_reply(&out_tx, &resp).await;
+            continue;
+        }
 
-                    let addr = format!("{}:{}", req.host, req.port);
-                    let stream = match TcpStream::connect(addr).await {
-                        Ok(s) => s,
-                        Err(e) => {
-                            let resp = TcpOpenResponse {
-                                r#type: "tcp_open".to_string(),
-                                id: req.id,
-                                stream_id: None,
-                                ok: false,
-                                error: Some(format!("connect error: {e}")),
-                            };
-                            let _ = out_tx_clone.send(serde_json::to_string(&resp).unwrap());
-                            continue;
-                        }
-                    };
+        if msg_type == "udp_bind" {
+            let req: UdpBindRequest = match serde_json::from_value(value) {
+                Ok(v) => v,
+                Err(e) => {
+                    log::warn!("Bad udp_bind payload: {e}");
+                    continue;
+                }
+            };
 
-                    let stream_id = next_stream_id;
-                    next_stream_id += 1;
-                    let use_tls = req.tls.unwrap_or(false);
-                    let insecure = req.insecure.unwrap_or(false);
-
-                    if use_tls {
-                        let server_name = req
-                            .server_name
-                            .clone()
-                            .unwrap_or_else(|| req.host.clone());
-                        let server_name = match ServerName::try_from(server_name.as_str()) {
-                            Ok(name) => name,
-                            Err(e) => {
-                                let resp = TcpOpenResponse {
-                                    r#type: "tcp_open".to_string(),
-                                    id: req.id,
-                                    stream_id: None,
-                                    ok: false,
-                                    error: Some(format!("bad server name: {e}")),
-                                };
-                                let _ = out_tx_clone.send(serde_json::to_string(&resp).unwrap());
-                                continue;
-                            }
-                        };
+            let v6 = match req.family.as_deref() {
+                None | Some("udp4") => false,
+                Some("udp6") => true,
+                Some(other) => {
+                    let resp = udp_bind_error(req.id, format!("unsupported family: {other}"));
+                    send_reply(&out_tx, &resp).await;
+                    continue;
+                }
+            };
+            let port = req.port.unwrap_or(0);
+            // A socket with neither host nor port is a client: it binds every
+            // interface so it can reach the network through egress, but only
+            // hears from peers it has sent to. Anything else is a server and
+            // follows [inbound].
+            let client_socket = req.host.is_none() && port == 0;
+            let host = match &req.host {
+                Some(host) => host.clone(),
+                None if client_socket => if v6 { "::" } else { "0.0.0.0" }.to_string(),
+                None => udp_default_host(&config.inbound.default_host, v6),
+            };
+            if !client_socket
+                && !config
+                    .inbound
+                    .allowed_hosts
+                    .iter()
+                    .any(|allowed| allowed.eq_ignore_ascii_case(&host))
+            {
+                let resp = udp_bind_error(req.id, format!("listen address not allowed: {host}"));
+                send_reply(&out_tx, &resp).await;
+                continue;
+            }
+            if udp_sockets.lock().unwrap().len() >= config.limits.max_udp_sockets {
+                let resp = udp_bind_error(req.id, "too many udp sockets".to_string());
+                send_reply(&out_tx, &resp).await;
+                continue;
+            }
 
-                        let cfg = match make_tls_config(insecure) {
-                            Ok(c) => c,
-                            Err(e) => {
-                                let resp = TcpOpenResponse {
-                                    r#type: "tcp_open".to_string(),
-                                    id: req.id,
-                                    stream_id: None,
-                                    ok: false,
-                                    error: Some(e),
-                                };
-                                let _ = out_tx_clone.send(serde_json::to_string(&resp).unwrap());
-                                continue;
-                            }
-                        };
+            let bare = host.trim_start_matches('[').trim_end_matches(']');
+            let socket = match UdpSocket::bind((bare, port)).await {
+                Ok(socket) => Arc::new(socket),
+                Err(e) => {
+                    let resp = udp_bind_error(req.id, format!("bind error: {e}"));
+                    send_reply(&out_tx, &resp).await;
+                    continue;
+                }
+            };
+            let local = socket.local_addr().ok();
+            let socket_id = next_stream_id.fetch_add(1, Ordering::Relaxed);
+            let (close, close_rx) = oneshot::channel();
+            let peers = client_socket.then(UdpPeers::default);
+            let sends = spawn_udp_sender(
+                socket.clone(),
+                peers.clone(),
+                out_tx.clone(),
+                config.clone(),
+            );
+            udp_sockets
+                .lock()
+                .unwrap()
+                .insert(socket_id, UdpEntry { sends, close });
+            spawn_udp_reader(
+                socket,
+                socket_id,
+                peers,
+                out_tx.clone(),
+                udp_sockets.clone(),
+                close_rx,
+            );
 
-                        let connector = TlsConnector::from(Arc::new(cfg));
-                        let tls_stream = match connector.connect(server_name, stream).await {
-                            Ok(s) => s,
-                            Err(e) => {
-                                let resp = TcpOpenResponse {
-                                    r#type: "tcp_open".to_string(),
-                                    id: req.id,
-                                    stream_id: None,
-                                    ok: false,
-                                    error: Some(format!("tls handshake error: {e}")),
-                                };
-                                let _ = out_tx_clone.send(serde_json::to_string(&resp).unwrap());
-                                continue;
-                            }
-                        };
+            let resp = UdpBindResponse {
+                r#type: "udp_bind".to_string(),
+                id: req.id,
+                socket_id: Some(socket_id),
+                ok: true,
+                local_address: local.map(|addr| addr.ip().to_string()),
+                local_port: local.map(|addr| addr.port()),
+                error: None,
+            };
+            send_reply(&out_tx, &resp).await;
+            continue;
+        }
 
-                        let (mut reader, writer) = tokio::io::split(tls_stream);
-                        streams.lock().await.insert(stream_id, StreamWriter::Tls(writer));
-
-                        let out_tx_reader = out_tx_clone.clone();
-                        let streams_reader = streams.clone();
-                        tokio::spawn(async move {
-                            let mut buf = vec![0u8; 16 * 1024];
-                            loop {
-                                match reader.read(&mut buf).await {
-                                    Ok(0) => {
-                                        let msg = TcpCloseMessage {
-                                            r#type: "tcp_close".to_string(),
-                                            stream_id,
-                                            error: None,
-                                        };
-                                        let _ = out_tx_reader.send(serde_json::to_string(&msg).unwrap());
-                                        streams_reader.lock().await.remove(&stream_id);
-                                        break;
-                                    }
-                                    Ok(n) => {
-                                        let data = general_purpose::STANDARD.encode(&buf[..n]);
-                                        let msg = TcpDataMessage {
-                                            r#type: "tcp_data".to_string(),
-                                            stream_id,
-                                            data,
-                                            data_encoding: "base64".to_string(),
-                                        };
-                                        let _ = out_tx_reader.send(serde_json::to_string(&msg).unwrap());
-                                    }
-                                    Err(e) => {
-                                        let msg = TcpCloseMessage {
-                                            r#type: "tcp_close".to_string(),
-                                            stream_id,
-                                            error: Some(format!("read error: {e}")),
-                                        };
-                                        let _ = out_tx_reader.send(serde_json::to_string(&msg).unwrap());
-                                        streams_reader.lock().await.remove(&stream_id);
-                                        break;
-                                    }
-                                }
-                            }
-                        });
-                    } else {
-                        let (mut reader, writer) = stream.into_split();
-                        streams.lock().await.insert(stream_id, StreamWriter::Plain(writer));
-
-                        let out_tx_reader = out_tx_clone.clone();
-                        let streams_reader = streams.clone();
-                        tokio::spawn(async move {
-                            let mut buf = vec![0u8; 16 * 1024];
-                            loop {
-                                match reader.read(&mut buf).await {
-                                    Ok(0) => {
-                                        let msg = TcpCloseMessage {
-                                            r#type: "tcp_close".to_string(),
-                                            stream_id,
-                                            error: None,
-                                        };
-                                        let _ = out_tx_reader.send(serde_json::to_string(&msg).unwrap());
-                                        streams_reader.lock().await.remove(&stream_id);
-                                        break;
-                                    }
-                                    Ok(n) => {
-                                        let data = general_purpose::STANDARD.encode(&buf[..n]);
-                                        let msg = TcpDataMessage {
-                                            r#type: "tcp_data".to_string(),
-                                            stream_id,
-                                            data,
-                                            data_encoding: "base64".to_string(),
-                                        };
-                                        let _ = out_tx_reader.send(serde_json::to_string(&msg).unwrap());
-                                    }
-                                    Err(e) => {
-                                        let msg = TcpCloseMessage {
-                                            r#type: "tcp_close".to_string(),
-                                            stream_id,
-                                            error: Some(format!("read error: {e}")),
-                                        };
-                                        let _ = out_tx_reader.send(serde_json::to_string(&msg).unwrap());
-                                        streams_reader.lock().await.remove(&stream_id);
-                                        break;
-                                    }
-                                }
-                            }
-                        });
-                    }
+        if msg_type == "udp_send" {
+            let req: UdpSendRequest = match serde_json::from_value(value) {
+                Ok(v) => v,
+                Err(e) => {
+                    log::warn!("Bad udp_send payload: {e}");
+                    continue;
+                }
+            };
 
-                    let resp = TcpOpenResponse {
-                        r#type: "tcp_open".to_string(),
-                        id: req.id,
-                        stream_id: Some(stream_id),
-                        ok: true,
-                        error: None,
-                    };
-                    let _ = out_tx_clone.send(serde_json::to_string(&resp).unwrap());
+            // The socket's sender task answers, so resolving and sending never
+            // hold up this loop.
+            let id = req.id;
+            let sends = udp_sockets
+                .lock()
+                .unwrap()
+                .get(&req.socket_id)
+                .map(|entry| entry.sends.clone());
+            let error = match sends.map(|sends| sends.try_send(req)) {
+                Some(Ok(())) => continue,
+                Some(Err(mpsc::error::TrySendError::Full(_))) => "send queue full",
+                Some(Err(mpsc::error::TrySendError::Closed(_))) | None => "unknown socket",
+            };
+            let resp = udp_send_response(id, Err((error.to_string(), None)));
+            send_reply(&out_tx, &resp).await;
+            continue;
+        }
+
+        if msg_type == "udp_close" {
+            let req: UdpCloseRequest = match serde_json::from_value(value) {
+                Ok(v) => v,
+                Err(e) => {
+                    log::warn!("Bad udp_close payload: {e}");
                     continue;
                 }
+            };
 
-                if msg_type == "tcp_write" {
-                    let req: TcpWriteRequest = match serde_json::from_value(value) {
-                        Ok(v) => v,
-                        Err(e) => {
-                            eprintln!("Bad tcp_write payload: {e}");
-                            continue;
-                        }
-                    };
+            // The reader answers with the final `udp_close`.
+            if let Some(entry) = udp_sockets.lock().unwrap().remove(&req.socket_id) {
+                let _ = entry.close.send("closed");
+            }
+            continue;
+        }
 
-                    let data = match decode_body(&req.data, &req.data_encoding) {
-                        Ok(b) => b,
-                        Err(e) => {
-                            let resp = TcpWriteResponse {
-                                r#type: "tcp_write".to_string(),
-                                id: req.id,
-                                ok: false,
-                                error: Some(e),
-                            };
-                            let _ = out_tx_clone.send(serde_json::to_string(&resp).unwrap());
-                            continue;
-                        }
-                    };
+        if msg_type == "dns_lookup" {
+            let req: DnsLookupRequest = match serde_json::from_value(value) {
+                Ok(v) => v,
+                Err(e) => {
+                    log::warn!("Bad dns_lookup payload: {e}");
+                    continue;
+                }
+            };
 
-                    let mut guard = streams.lock().await;
-                    let write_res = match guard.get_mut(&req.stream_id) {
-                        Some(StreamWriter::Plain(writer)) => writer.write_all(&data).await,
-                        Some(StreamWriter::Tls(writer)) => writer.write_all(&data).await,
-                        None => {
-                            let resp = TcpWriteResponse {
-                                r#type: "tcp_write".to_string(),
-                                id: req.id,
-                                ok: false,
-                                error: Some("unknown stream".to_string()),
-                            };
-                            let _ = out_tx_clone.send(serde_json::to_string(&resp).unwrap());
-                            continue;
-                        }
-                    };
+            let out_tx_dns = out_tx.clone();
+            tokio::spawn(async move {
+                let resp = match dns::lookup(&req.hostname, req.family).await {
+                    Ok(addrs) => DnsLookupResponse {
+                        r#type: "dns_lookup".to_string(),
+                        id: req.id,
+                        ok: true,
+                        addresses: addrs
+                            .into_iter()
+                            .map(|ip| DnsAddress {
+                                address: ip.to_string(),
+                                family: if ip.is_ipv4() { 4 } else { 6 },
+                            })
+                            .collect(),
+                        error: None,
+                        code: None,
+                    },
+                    Err(e) => DnsLookupResponse {
+                        r#type: "dns_lookup".to_string(),
+                        id: req.id,
+                        ok: false,
+                        addresses: Vec::new(),
+                        error: Some(e.message),
+                        code: Some(e.code.to_string()),
+                    },
+                };
+                send_reply(&out_tx_dns, &resp).await;
+            });
+            continue;
+        }
 
-                    if let Err(e) = write_res {
-                        let resp = TcpWriteResponse {
-                            r#type: "tcp_write".to_string(),
-                            id: req.id,
-                            ok: false,
-                            error: Some(format!("write error: {e}")),
-                        };
-                        let _ = out_tx_clone.send(serde_json::to_string(&resp).unwrap());
-                        continue;
-                    }
+        if msg_type == "dns_resolve" {
+            let req: DnsResolveRequest = match serde_json::from_value(value) {
+                Ok(v) => v,
+                Err(e) => {
+                    log::warn!("Bad dns_resolve payload: {e}");
+                    continue;
+                }
+            };
 
-                    let resp = TcpWriteResponse {
-                        r#type: "tcp_write".to_string(),
+            let out_tx_dns = out_tx.clone();
+            tokio::spawn(async move {
+                let rrtype = req.rrtype.as_deref().unwrap_or("A");
+                let resp = match dns::resolve(&req.hostname, rrtype).await {
+                    Ok(records) => DnsResolveResponse {
+                        r#type: "dns_resolve".to_string(),
                         id: req.id,
                         ok: true,
+                        records,
                         error: None,
-                    };
-                    let _ = out_tx_clone.send(serde_json::to_string(&resp).unwrap());
+                        code: None,
+                    },
+                    Err(e) => DnsResolveResponse {
+                        r#type: "dns_resolve".to_string(),
+                        id: req.id,
+                        ok: false,
+                        records: Vec::new(),
+                        error: Some(e.message),
+                        code: Some(e.code.to_string()),
+                    },
+                };
+                send_reply(&out_tx_dns, &resp).await;
+            });
+            continue;
+        }
+
+        if msg_type == "cookie_list" {
+            let req: CookieListRequest = match serde_json::from_value(value) {
+                Ok(v) => v,
+                Err(e) => {
+                    log::warn!("Bad cookie_list payload: {e}");
                     continue;
                 }
+            };
 
-                if msg_type == "tcp_close" {
-                    let req: TcpCloseRequest = match serde_json::from_value(value) {
-                        Ok(v) => v,
-                        Err(e) => {
-                            eprintln!("Bad tcp_close payload: {e}");
-                            continue;
-                        }
-                    };
+            let listed = req
+                .url
+                .as_deref()
+                .map(|url| reqwest::Url::parse(url).map_err(|e| format!("invalid url: {e}")))
+                .transpose()
+                .and_then(|url| Ok(cookie_jar(&client, &req.cookie_jar)?.list(url.as_ref())));
+            let resp = CookieListResponse {
+                r#type: "cookie_list".to_string(),
+                id: req.id,
+                ok: listed.is_ok(),
+                error: listed.as_ref().err().cloned(),
+                cookies: listed.unwrap_or_default(),
+            };
+            send_reply(&out_tx, &resp).await;
+            continue;
+        }
 
-                    streams.lock().await.remove(&req.stream_id);
-                    let msg = TcpCloseMessage {
-                        r#type: "tcp_close".to_string(),
-                        stream_id: req.stream_id,
-                        error: None,
-                    };
-                    let _ = out_tx_clone.send(serde_json::to_string(&msg).unwrap());
+        if msg_type == "cookie_set" {
+            let req: CookieSetRequest = match serde_json::from_value(value) {
+                Ok(v) => v,
+                Err(e) => {
+                    log::warn!("Bad cookie_set payload: {e}");
+                    continue;
+                }
+            };
+
+            let result = reqwest::Url::parse(&req.url)
+                .map_err(|e| format!("invalid url: {e}"))
+                .and_then(|url| cookie_jar(&client, &req.cookie_jar)?.set(&url, &req.cookie));
+            let resp = CookieResponse {
+                r#type: "cookie_set".to_string(),
+                id: req.id,
+                ok: result.is_ok(),
+                error: result.err(),
+            };
+            send_reply(&out_tx, &resp).await;
+            continue;
+        }
+
+        if msg_type == "cookie_clear" {
+            let req: CookieClearRequest = match serde_json::from_value(value) {
+                Ok(v) => v,
+                Err(e) => {
+                    log::warn!("Bad cookie_clear payload: {e}");
                     continue;
                 }
+            };
+
+            let result =
+                cookie_jar(&client, &req.cookie_jar).map(|jar| jar.clear(req.domain.as_deref()));
+            let resp = CookieResponse {
+                r#type: "cookie_clear".to_string(),
+                id: req.id,
+                ok: result.is_ok(),
+                error: result.err(),
+            };
+            send_reply(&out_tx, &resp).await;
+            continue;
+        }
+
+        if msg_type == "binary_frames" {
+            let req: BinaryFramesRequest = match serde_json::from_value(value) {
+                Ok(v) => v,
+                Err(e) => {
+                    log::warn!("Bad binary_frames payload: {e}");
+                    continue;
+                }
+            };
+
+            binary_frames.store(req.enabled, Ordering::Relaxed);
+            let resp = BinaryFramesResponse {
+                r#type: "binary_frames".to_string(),
+                id: req.id,
+                ok: true,
+                enabled: req.enabled,
+            };
+            send_reply(&out_tx, &resp).await;
+            continue;
+        }
+
+        if msg_type == "session_ack" {
+            let req: SessionAckRequest = match serde_json::from_value(value) {
+                Ok(v) => v,
+                Err(e) => {
+                    log::warn!("Bad session_ack payload: {e}");
+                    continue;
+                }
+            };
+
+            replay.lock().unwrap().ack(req.received);
+            continue;
+        }
+
+        if msg_type == "tcp_close" {
+            let req: TcpCloseRequest = match serde_json::from_value(value) {
+                Ok(v) => v,
+                Err(e) => {
+                    log::warn!("Bad tcp_close payload: {e}");
+                    continue;
+                }
+            };
+
+            // The reader answers with the final `tcp_close`; a stream that
+            // already closed on its own has sent it.
+            if let Some(entry) = streams.lock().await.remove(&req.stream_id) {
+                let _ = entry.close.send(ReaderStop::Close("closed"));
+            }
+            continue;
+        }
+    }
+
+    let state = SessionState {
+        out_tx,
+        replay,
+        client,
+        streams,
+        binary_frames,
+        fetches,
+        uploads,
+        next_stream_id,
+        listeners,
+        next_listener_id,
+        udp_sockets,
+        received,
+    };
+    let Some(token) = token else {
+        close_session(state).await;
+        let _ = writer.await;
+        drop(stop);
+        log::debug!("session from {peer} closed");
+        return;
+    };
+    let _ = stop.send(());
+    let Ok(out_rx) = writer.await else {
+        sessions.lock().unwrap().remove(&token);
+        close_session(state).await;
+        return;
+    };
+    let session = DetachedSession { state, out_rx };
+    // Under the lock, a takeover is either already waiting here or will find
+    // the session parked.
+    let unwanted = {
+        let mut guard = sessions.lock().unwrap();
+        match handover.or_else(|| takeover.try_recv().ok()) {
+            Some(tx) => tx.send(session).err(),
+            None if ended => {
+                guard.remove(&token);
+                Some(session)
+            }
+            None => {
+                let timeout = Duration::from_secs(config.limits.resume_timeout_secs);
+                park_session(&mut guard, &sessions, token, session, timeout);
+                log::debug!("session from {peer} parked");
+                None
             }
+        }
+    };
+    if let Some(session) = unwanted {
+        close_session(session.state).await;
+        log::debug!("session from {peer} closed");
+    }
+}
 
-            let _ = writer.await;
+async fn serve(
+    listener: TcpListener,
+    acceptor: Option<TlsAcceptor>,
+    config: Arc<Config>,
+    sessions: SessionRegistry,
+) -> std::io::Result<()> {
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let acceptor = acceptor.clone();
+        let config = config.clone();
+        let sessions = sessions.clone();
+        tokio::spawn(async move {
+            let stream = match acceptor {
+                Some(acceptor) => match acceptor.accept(stream).await {
+                    Ok(tls_stream) => ServerStream::Tls(Box::new(tls_stream)),
+                    Err(e) => {
+                        log::info!("TLS handshake with {peer} failed: {e}");
+                        return;
+                    }
+                },
+                None => ServerStream::Plain(stream),
+            };
+            handle_session(stream, peer, config, sessions).await;
         });
     }
 }
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut config = Config::load(Cli::parse())?;
+    env_logger::Builder::new()
+        .parse_filters(&config.log_level)
+        .init();
+    if config.auth.enabled && config.auth.token.is_none() {
+        let token = auth::generate_token()?;
+        println!("Proxy auth token: {token}");
+        config.auth.token = Some(token);
+    }
+    tls::init(&config.tls)?;
+    cookies::init(&config.cookies)?;
+    let acceptor = if config.server_tls.enabled {
+        let (server_config, fingerprint) = tls::server_config(&config.server_tls)?;
+        if let Some(fingerprint) = fingerprint {
+            println!("Self-signed certificate SHA-256 fingerprint: {fingerprint}");
+        }
+        Some(TlsAcceptor::from(Arc::new(server_config)))
+    } else {
+        None
+    };
+    let scheme = if acceptor.is_some() { "wss" } else { "ws" };
+    let config = Arc::new(config);
+
+    let sessions: SessionRegistry = Arc::new(std::sync::Mutex::new(HashMap::new()));
+    let mut listeners = Vec::new();
+    for addr in config.listen_addrs()? {
+        let listener = TcpListener::bind(addr).await?;
+        log::info!("WS proxy listening on {scheme}://{addr}");
+        listeners.push(serve(
+            listener,
+            acceptor.clone(),
+            config.clone(),
+            sessions.clone(),
+        ));
+    }
+
+    futures_util::future::try_join_all(listeners).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request_headers(json: serde_json::Value) -> Result<HeaderMap, String> {
+        request_header_map(&Some(serde_json::from_value(json).unwrap()))
+    }
+
+    #[test]
+    fn byte_strings_round_trip_every_byte() {
+        let bytes: Vec<u8> = (0..=255).collect();
+        let text = byte_string(&bytes);
+        assert_eq!(text.chars().count(), 256);
+        assert_eq!(byte_string_bytes(&text), Some(bytes));
+        assert_eq!(byte_string_bytes("caf\u{e9}"), Some(b"caf\xe9".to_vec()));
+        assert_eq!(byte_string_bytes("\u{20ac}"), None);
+    }
+
+    #[test]
+    fn request_header_lists_keep_repeats_in_order() {
+        let map = request_headers(serde_json::json!([
+            ["Accept", "text/html"],
+            ["x-tag", "one"],
+            ["X-Tag", "two"],
+        ]))
+        .unwrap();
+        let tags: Vec<&[u8]> = map.get_all("x-tag").iter().map(|v| v.as_bytes()).collect();
+        assert_eq!(tags, [b"one".as_slice(), b"two".as_slice()]);
+        assert_eq!(map.get("accept").unwrap(), "text/html");
+    }
+
+    #[test]
+    fn request_header_objects_still_work() {
+        let map = request_headers(serde_json::json!({"x-a": "1", "x-b": "2"})).unwrap();
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.get("x-b").unwrap(), "2");
+    }
+
+    #[test]
+    fn request_header_values_are_byte_strings() {
+        let map = request_headers(serde_json::json!([["x-name", "caf\u{e9}"]])).unwrap();
+        assert_eq!(map.get("x-name").unwrap().as_bytes(), b"caf\xe9");
+        assert!(request_headers(serde_json::json!([["x-name", "\u{20ac}"]])).is_err());
+        assert!(request_headers(serde_json::json!([["x-name", "a\nb"]])).is_err());
+        assert!(request_headers(serde_json::json!([["bad name", "a"]])).is_err());
+    }
+
+    #[test]
+    fn response_headers_keep_repeats_and_raw_bytes() {
+        let mut map = HeaderMap::new();
+        map.append("set-cookie", HeaderValue::from_static("a=1"));
+        map.append("link", HeaderValue::from_static("<x>"));
+        map.append("set-cookie", HeaderValue::from_static("b=2"));
+        map.append("x-raw", HeaderValue::from_bytes(b"caf\xe9\xff").unwrap());
+        let headers = response_headers(&map);
+
+        assert_eq!(headers.headers["set-cookie"], "a=1, b=2");
+        assert_eq!(headers.headers["x-raw"], "caf\u{e9}\u{ff}");
+        let raw: Vec<(&str, &str)> = headers
+            .raw_headers
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect();
+        assert_eq!(
+            raw,
+            [
+                ("set-cookie", "a=1"),
+                ("set-cookie", "b=2"),
+                ("link", "<x>"),
+                ("x-raw", "caf\u{e9}\u{ff}"),
+            ]
+        );
+        // What a client decodes is what the server sent.
+        let (_, value) = &headers.raw_headers[3];
+        assert_eq!(byte_string_bytes(value).unwrap(), b"caf\xe9\xff");
+    }
+
+    #[test]
+    fn response_headers_serialize_as_pairs() {
+        let mut map = HeaderMap::new();
+        map.append("vary", HeaderValue::from_static("accept"));
+        map.append("vary", HeaderValue::from_static("origin"));
+        let json = serde_json::to_value(response_headers(&map)).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "headers": {"vary": "accept, origin"},
+                "rawHeaders": [["vary", "accept"], ["vary", "origin"]],
+            })
+        );
+    }
+}
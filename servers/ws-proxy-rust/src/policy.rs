@@ -0,0 +1,316 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+
+use hyper::client::connect::dns::Name;
+use reqwest::dns::{Addrs, Resolve, Resolving};
+
+use crate::auth::wildcard_match;
+use crate::config::{Action, Egress, EgressRule};
+
+/// Why `resolve` gave no address to connect to.
+#[derive(Debug)]
+pub enum EgressError {
+    /// The rules admit none of the destination's addresses.
+    Denied(String),
+    /// The host name did not resolve.
+    Unresolved(String),
+}
+
+impl std::fmt::Display for EgressError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Denied(target) => write!(f, "egress denied: {target}"),
+            Self::Unresolved(e) => write!(f, "resolve error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for EgressError {}
+
+impl EgressError {
+    /// Code for the `code` field of error responses, where there is one.
+    pub fn code(&self) -> Option<&'static str> {
+        match self {
+            Self::Denied(_) => Some("EGRESS_DENIED"),
+            Self::Unresolved(_) => None,
+        }
+    }
+}
+
+impl From<EgressError> for String {
+    fn from(e: EgressError) -> Self {
+        e.to_string()
+    }
+}
+
+/// What the rules say about one resolved destination.
+pub fn decide(egress: &Egress, host: &str, ip: IpAddr, port: u16, scheme: &str) -> Action {
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    // IPv4-mapped IPv6 addresses must not slip past IPv4 ranges.
+    let ip = ip.to_canonical();
+    egress
+        .rules
+        .iter()
+        .find(|rule| rule_matches(rule, &host, ip, port, scheme))
+        .map_or(egress.default, |rule| rule.action)
+}
+
+fn rule_matches(rule: &EgressRule, host: &str, ip: IpAddr, port: u16, scheme: &str) -> bool {
+    (rule.hosts.is_empty()
+        || rule
+            .hosts
+            .iter()
+            .any(|pattern| wildcard_match(&pattern.to_ascii_lowercase(), host)))
+        && (rule.cidrs.is_empty() || rule.cidrs.iter().any(|net| net.contains(&ip)))
+        && (rule.ports.is_empty()
+            || rule
+                .ports
+                .iter()
+                .any(|range| (range.first..=range.last).contains(&port)))
+        && (rule.schemes.is_empty() || rule.schemes.iter().any(|s| s.eq_ignore_ascii_case(scheme)))
+}
+
+/// Resolves `host` and keeps the addresses the policy admits. Callers must
+/// connect to exactly these rather than resolve again, or a rebinding DNS
+/// server could swap in a denied address after the check.
+pub async fn resolve(
+    egress: &Egress,
+    host: &str,
+    port: u16,
+    scheme: &str,
+) -> Result<Vec<SocketAddr>, EgressError> {
+    let bare = host.trim_start_matches('[').trim_end_matches(']');
+    let addrs: Vec<SocketAddr> = match bare.parse::<IpAddr>() {
+        Ok(ip) => vec![SocketAddr::new(ip, port)],
+        Err(_) => tokio::net::lookup_host((bare, port))
+            .await
+            .map_err(|e| EgressError::Unresolved(e.to_string()))?
+            .collect(),
+    };
+    let allowed: Vec<SocketAddr> = addrs
+        .into_iter()
+        .filter(|addr| decide(egress, bare, addr.ip(), port, scheme) == Action::Allow)
+        .collect();
+    if allowed.is_empty() {
+        log::warn!("Denied egress to {scheme}://{host}:{port}");
+        return Err(EgressError::Denied(format!("{scheme}://{host}:{port}")));
+    }
+    Ok(allowed)
+}
+
+/// DNS resolver for a fetch client. It only hands out addresses `resolve`
+/// approved, so reqwest never connects anywhere the policy has not seen.
+/// Pins are per host name and the latest approval for a host wins, so a
+/// resolver must only serve one scheme and port: the verdict depends on them.
+#[derive(Default)]
+pub struct PinnedResolver {
+    pins: Mutex<HashMap<String, Vec<SocketAddr>>>,
+}
+
+impl PinnedResolver {
+    pub fn pin(&self, host: &str, addrs: Vec<SocketAddr>) {
+        self.pins
+            .lock()
+            .unwrap()
+            .insert(host.to_ascii_lowercase(), addrs);
+    }
+}
+
+impl Resolve for PinnedResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let addrs = self
+            .pins
+            .lock()
+            .unwrap()
+            .get(&name.as_str().to_ascii_lowercase())
+            .cloned();
+        Box::pin(async move {
+            let addrs =
+                addrs.ok_or_else(|| EgressError::Denied(format!("{name} was not checked")))?;
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn egress(toml: &str) -> Egress {
+        toml::from_str(toml).unwrap()
+    }
+
+    fn check(egress: &Egress, host: &str, ip: &str, port: u16, scheme: &str) -> Action {
+        decide(egress, host, ip.parse().unwrap(), port, scheme)
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let egress = egress(
+            r#"
+            default = "deny"
+            [[rules]]
+            action = "deny"
+            hosts = ["admin.example.com"]
+            [[rules]]
+            action = "allow"
+            hosts = ["*.example.com"]
+            [[rules]]
+            action = "deny"
+            hosts = ["www.example.com"]
+            "#,
+        );
+        assert_eq!(
+            check(&egress, "admin.example.com", "192.0.2.1", 443, "https"),
+            Action::Deny
+        );
+        assert_eq!(
+            check(&egress, "www.example.com", "192.0.2.1", 443, "https"),
+            Action::Allow
+        );
+        assert_eq!(
+            check(&egress, "example.org", "192.0.2.1", 443, "https"),
+            Action::Deny
+        );
+    }
+
+    #[test]
+    fn default_applies_when_nothing_matches() {
+        let egress = egress(
+            r#"
+            default = "allow"
+            [[rules]]
+            action = "deny"
+            cidrs = ["10.0.0.0/8"]
+            "#,
+        );
+        assert_eq!(check(&egress, "a", "10.1.2.3", 80, "http"), Action::Deny);
+        assert_eq!(check(&egress, "a", "192.0.2.1", 80, "http"), Action::Allow);
+    }
+
+    #[test]
+    fn every_criterion_must_match() {
+        let egress = egress(
+            r#"
+            default = "deny"
+            [[rules]]
+            action = "allow"
+            hosts = ["*.internal"]
+            cidrs = ["10.0.0.0/8"]
+            ports = [443]
+            schemes = ["https"]
+            "#,
+        );
+        assert_eq!(
+            check(&egress, "db.internal", "10.0.0.5", 443, "https"),
+            Action::Allow
+        );
+        assert_eq!(
+            check(&egress, "db.example", "10.0.0.5", 443, "https"),
+            Action::Deny
+        );
+        assert_eq!(
+            check(&egress, "db.internal", "11.0.0.5", 443, "https"),
+            Action::Deny
+        );
+        assert_eq!(
+            check(&egress, "db.internal", "10.0.0.5", 444, "https"),
+            Action::Deny
+        );
+        assert_eq!(
+            check(&egress, "db.internal", "10.0.0.5", 443, "http"),
+            Action::Deny
+        );
+    }
+
+    #[test]
+    fn hosts_and_schemes_ignore_case_and_trailing_dot() {
+        let egress = egress(
+            r#"
+            default = "deny"
+            [[rules]]
+            action = "allow"
+            hosts = ["API.Example.com"]
+            schemes = ["HTTPS"]
+            "#,
+        );
+        assert_eq!(
+            check(&egress, "api.example.COM.", "192.0.2.1", 443, "https"),
+            Action::Allow
+        );
+    }
+
+    #[test]
+    fn port_ranges_are_inclusive() {
+        let egress = egress(
+            r#"
+            default = "deny"
+            [[rules]]
+            action = "allow"
+            ports = ["8000-8080", 22]
+            "#,
+        );
+        for (port, action) in [
+            (7999, Action::Deny),
+            (8000, Action::Allow),
+            (8042, Action::Allow),
+            (8080, Action::Allow),
+            (8081, Action::Deny),
+            (22, Action::Allow),
+        ] {
+            assert_eq!(
+                check(&egress, "h", "192.0.2.1", port, "tcp"),
+                action,
+                "port {port}"
+            );
+        }
+    }
+
+    #[test]
+    fn ipv6_ranges_and_mapped_addresses() {
+        let egress = egress(
+            r#"
+            default = "allow"
+            [[rules]]
+            action = "deny"
+            cidrs = ["fe80::/10", "169.254.0.0/16"]
+            "#,
+        );
+        assert_eq!(check(&egress, "h", "fe80::1", 80, "http"), Action::Deny);
+        assert_eq!(
+            check(&egress, "h", "2001:db8::1", 80, "http"),
+            Action::Allow
+        );
+        // An IPv4-mapped address is judged as the IPv4 address it carries.
+        assert_eq!(
+            check(&egress, "h", "::ffff:169.254.169.254", 80, "http"),
+            Action::Deny
+        );
+    }
+
+    #[test]
+    fn default_rules_block_metadata_endpoints() {
+        let egress = Egress::default();
+        assert_eq!(
+            check(&egress, "h", "169.254.169.254", 80, "http"),
+            Action::Deny
+        );
+        assert_eq!(
+            check(&egress, "h", "fd00:ec2::254", 80, "http"),
+            Action::Deny
+        );
+        assert_eq!(check(&egress, "h", "127.0.0.1", 80, "http"), Action::Allow);
+    }
+
+    #[tokio::test]
+    async fn resolve_reports_denials_apart() {
+        let egress = egress(r#"default = "deny""#);
+        let denied = resolve(&egress, "127.0.0.1", 80, "http").await;
+        assert!(matches!(denied, Err(EgressError::Denied(_))));
+        let allowed = resolve(&Egress::default(), "[::1]", 80, "http")
+            .await
+            .unwrap();
+        assert_eq!(allowed, vec!["[::1]:80".parse().unwrap()]);
+    }
+}
@@ -0,0 +1,182 @@
+use std::collections::VecDeque;
+
+use tokio_tungstenite::tungstenite::handshake::server::Request;
+use tokio_tungstenite::tungstenite::Message;
+
+/// What a client asked for with `?session=` on the WebSocket URL.
+pub enum SessionRequest {
+    /// `?session=new`: a fresh session that survives disconnects.
+    New,
+    /// `?session=<token>&received=<n>`: reattach to a parked session, having
+    /// seen its first `n` frames.
+    Resume { token: String, received: u64 },
+}
+
+pub fn request_session(req: &Request) -> Option<SessionRequest> {
+    let query = req.uri().query()?;
+    let param = |name: &str| {
+        query.split('&').find_map(|pair| {
+            pair.strip_prefix(name)
+                .and_then(|rest| rest.strip_prefix('='))
+        })
+    };
+    match param("session")? {
+        "new" => Some(SessionRequest::New),
+        token => Some(SessionRequest::Resume {
+            token: token.to_string(),
+            received: param("received").and_then(|n| n.parse().ok()).unwrap_or(0),
+        }),
+    }
+}
+
+/// Frames already handed to a WebSocket, kept so they can be sent again if
+/// the connection dies before the client saw them. Frames are numbered from
+/// 1 in the order they were sent; the oldest are dropped once the log holds
+/// more than `limit` bytes.
+pub struct ReplayLog {
+    frames: VecDeque<Message>,
+    /// Number the next recorded frame gets.
+    next: u64,
+    bytes: usize,
+    limit: usize,
+}
+
+impl ReplayLog {
+    /// A `limit` of 0 only counts frames, for sessions that cannot resume.
+    pub fn new(limit: usize) -> Self {
+        Self {
+            frames: VecDeque::new(),
+            next: 1,
+            bytes: 0,
+            limit,
+        }
+    }
+
+    pub fn record(&mut self, msg: &Message) {
+        self.next += 1;
+        if self.limit == 0 {
+            return;
+        }
+        self.bytes += msg.len();
+        self.frames.push_back(msg.clone());
+        while self.bytes > self.limit {
+            let Some(old) = self.frames.pop_front() else {
+                break;
+            };
+            self.bytes -= old.len();
+        }
+    }
+
+    /// Number of the oldest frame still held.
+    fn first(&self) -> u64 {
+        self.next - self.frames.len() as u64
+    }
+
+    /// Forgets the frames the client has received.
+    pub fn ack(&mut self, received: u64) {
+        let done = (received + 1).saturating_sub(self.first());
+        for _ in 0..done.min(self.frames.len() as u64) {
+            if let Some(old) = self.frames.pop_front() {
+                self.bytes -= old.len();
+            }
+        }
+    }
+
+    /// The frames after `received`, or `None` when some of them were
+    /// already dropped or `received` counts frames never sent.
+    pub fn resume(&mut self, received: u64) -> Option<Vec<Message>> {
+        if received + 1 < self.first() || received >= self.next {
+            return None;
+        }
+        self.ack(received);
+        Some(self.frames.iter().cloned().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log_of(limit: usize, frames: &[&str]) -> ReplayLog {
+        let mut log = ReplayLog::new(limit);
+        for frame in frames {
+            log.record(&Message::Text(frame.to_string()));
+        }
+        log
+    }
+
+    fn texts(frames: Option<Vec<Message>>) -> Option<Vec<String>> {
+        frames.map(|frames| frames.into_iter().map(|m| m.into_text().unwrap()).collect())
+    }
+
+    #[test]
+    fn resume_replays_what_was_missed() {
+        let mut log = log_of(1024, &["a", "b", "c"]);
+        assert_eq!(texts(log.resume(1)), Some(vec!["b".into(), "c".into()]));
+        // Resuming forgot frame 1, so a second resume from 0 has a gap.
+        assert_eq!(texts(log.resume(0)), None);
+        assert_eq!(texts(log.resume(3)), Some(vec![]));
+    }
+
+    #[test]
+    fn resume_rejects_frames_never_sent() {
+        let mut log = log_of(1024, &["a", "b"]);
+        assert_eq!(texts(log.resume(3)), None);
+        assert_eq!(texts(log.resume(2)), Some(vec![]));
+    }
+
+    #[test]
+    fn ack_forgets_received_frames() {
+        let mut log = log_of(1024, &["a", "b", "c", "d"]);
+        log.ack(2);
+        assert_eq!(texts(log.resume(1)), None);
+        assert_eq!(texts(log.resume(2)), Some(vec!["c".into(), "d".into()]));
+        // Acks for frames already forgotten change nothing.
+        log.ack(1);
+        assert_eq!(texts(log.resume(2)), Some(vec!["c".into(), "d".into()]));
+    }
+
+    #[test]
+    fn ack_past_the_window_empties_it() {
+        let mut log = log_of(1024, &["a", "b"]);
+        log.ack(10);
+        assert_eq!(log.bytes, 0);
+        assert_eq!(texts(log.resume(2)), Some(vec![]));
+        log.record(&Message::Text("c".into()));
+        assert_eq!(texts(log.resume(2)), Some(vec!["c".into()]));
+    }
+
+    #[test]
+    fn limit_drops_oldest_frames() {
+        let mut log = log_of(3, &["a", "b", "c", "d", "e"]);
+        assert_eq!(log.bytes, 3);
+        assert_eq!(texts(log.resume(1)), None);
+        assert_eq!(
+            texts(log.resume(2)),
+            Some(vec!["c".into(), "d".into(), "e".into()])
+        );
+    }
+
+    #[test]
+    fn zero_limit_only_counts() {
+        let mut log = log_of(0, &["a", "b"]);
+        assert_eq!(texts(log.resume(0)), None);
+        assert_eq!(texts(log.resume(2)), Some(vec![]));
+    }
+
+    #[test]
+    fn session_query_parameters() {
+        let request = |uri: &str| Request::builder().uri(uri).body(()).unwrap();
+        assert!(matches!(
+            request_session(&request("/?session=new")),
+            Some(SessionRequest::New)
+        ));
+        match request_session(&request("/?token=x&session=abc&received=7")) {
+            Some(SessionRequest::Resume { token, received }) => {
+                assert_eq!((token.as_str(), received), ("abc", 7));
+            }
+            _ => panic!("expected a resume"),
+        }
+        assert!(request_session(&request("/?token=x")).is_none());
+    }
+}
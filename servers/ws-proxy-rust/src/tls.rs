@@ -0,0 +1,503 @@
+use std::io;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::task::{Context, Poll};
+
+use base64::{engine::general_purpose, Engine as _};
+use ring::digest;
+use rustls::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
+use rustls::{
+    Certificate, CertificateError, ClientConfig, ClientConnection, Error as TlsError, PrivateKey,
+    ProtocolVersion, RootCertStore, ServerConfig, ServerName,
+};
+use rustls_pemfile::Item;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use x509_parser::extensions::GeneralName;
+use x509_parser::objects::{oid2sn, oid_registry};
+use x509_parser::time::ASN1Time;
+use x509_parser::x509::X509Name;
+
+use crate::config::{ClientCertFiles, ServerTls, Tls};
+
+/// Outcome of the server certificate check: `None` once the chain verified,
+/// or the Node error code saying why it did not.
+pub type Verdict = Arc<Mutex<Option<String>>>;
+
+/// Verifier behind `insecure`: checks the chain as usual but only notes the
+/// result, so the connection still reports `authorized: false` the way
+/// Node's `rejectUnauthorized: false` does.
+struct RecordingVerifier {
+    inner: WebPkiVerifier,
+    verdict: Verdict,
+}
+
+impl ServerCertVerifier for RecordingVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: std::time::SystemTime,
+    ) -> Result<ServerCertVerified, TlsError> {
+        let result = self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            scts,
+            ocsp_response,
+            now,
+        );
+        *self.verdict.lock().unwrap() = result.err().map(|e| authorization_error(&e));
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        _message: &[u8],
+        _cert: &Certificate,
+        _dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::HandshakeSignatureValid, TlsError> {
+        Ok(rustls::client::HandshakeSignatureValid::assertion())
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        _message: &[u8],
+        _cert: &Certificate,
+        _dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::HandshakeSignatureValid, TlsError> {
+        Ok(rustls::client::HandshakeSignatureValid::assertion())
+    }
+}
+
+/// The `authorizationError` code Node uses for the same failure.
+fn authorization_error(err: &TlsError) -> String {
+    let code = match err {
+        TlsError::InvalidCertificate(e) => match e {
+            CertificateError::UnknownIssuer => "UNABLE_TO_VERIFY_LEAF_SIGNATURE",
+            CertificateError::Expired => "CERT_HAS_EXPIRED",
+            CertificateError::NotValidYet => "CERT_NOT_YET_VALID",
+            CertificateError::Revoked => "CERT_REVOKED",
+            CertificateError::BadSignature => "CERT_SIGNATURE_FAILURE",
+            CertificateError::NotValidForName => "ERR_TLS_CERT_ALTNAME_INVALID",
+            CertificateError::InvalidPurpose => "INVALID_PURPOSE",
+            _ => return err.to_string(),
+        },
+        _ => return err.to_string(),
+    };
+    code.to_string()
+}
+
+static DEFAULT_ROOTS: OnceLock<RootCertStore> = OnceLock::new();
+
+/// Loads the configured TLS material: the roots connections trust unless a
+/// request brings its own `ca`, and (to check them early) the named client
+/// certificates. Call once at startup.
+pub fn init(tls: &Tls) -> Result<(), String> {
+    let mut roots = webpki_roots();
+    if tls.system_roots {
+        // On by default, so a host without a readable store only loses it.
+        match rustls_native_certs::load_native_certs() {
+            Ok(certs) => {
+                let (added, ignored) = roots.add_parsable_certificates(&certs);
+                log::info!("Loaded {added} system roots ({ignored} unusable)");
+            }
+            Err(e) => log::warn!("Cannot load system roots: {e}"),
+        }
+    }
+    for path in &tls.ca_files {
+        let pem = std::fs::read_to_string(path)
+            .map_err(|e| format!("cannot read {}: {e}", path.display()))?;
+        for cert in parse_certs(&pem, "ca")? {
+            roots
+                .add(&cert)
+                .map_err(|e| format!("invalid ca in {}: {e}", path.display()))?;
+        }
+    }
+    for (name, files) in &tls.client_certs {
+        ClientIdentity::load(files).map_err(|e| format!("client cert {name}: {e}"))?;
+    }
+    let _ = DEFAULT_ROOTS.set(roots);
+    Ok(())
+}
+
+fn webpki_roots() -> RootCertStore {
+    let mut roots = RootCertStore::empty();
+    roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+        rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
+            ta.subject,
+            ta.spki,
+            ta.name_constraints,
+        )
+    }));
+    roots
+}
+
+/// Every certificate in `pem`; `what` names it in errors.
+fn parse_certs(pem: &str, what: &str) -> Result<Vec<Certificate>, String> {
+    let certs =
+        rustls_pemfile::certs(&mut pem.as_bytes()).map_err(|e| format!("invalid {what}: {e}"))?;
+    if certs.is_empty() {
+        return Err(format!("{what} PEM holds no certificate"));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+/// The first unencrypted PKCS#8, PKCS#1 or SEC1 key in `pem`.
+fn parse_key(pem: &str, what: &str) -> Result<PrivateKey, String> {
+    let mut reader = pem.as_bytes();
+    loop {
+        match rustls_pemfile::read_one(&mut reader).map_err(|e| format!("invalid {what}: {e}"))? {
+            Some(Item::PKCS8Key(der) | Item::RSAKey(der) | Item::ECKey(der)) => {
+                return Ok(PrivateKey(der))
+            }
+            Some(_) => continue,
+            None => return Err(format!("{what} PEM holds no private key")),
+        }
+    }
+}
+
+fn read_pem(path: &Path) -> Result<String, String> {
+    std::fs::read_to_string(path).map_err(|e| format!("cannot read {}: {e}", path.display()))
+}
+
+/// A client certificate chain and its key, presented for mutual TLS.
+pub struct ClientIdentity {
+    certs: Vec<Certificate>,
+    key: PrivateKey,
+}
+
+impl ClientIdentity {
+    /// Parses PEM text as Node's `tls.connect({cert, key})` takes it: the
+    /// chain leaf first, and an unencrypted PKCS#8, PKCS#1 or SEC1 key.
+    pub fn from_pem(cert: &str, key: &str) -> Result<Self, String> {
+        Ok(Self {
+            certs: parse_certs(cert, "client certificate")?,
+            key: parse_key(key, "client key")?,
+        })
+    }
+
+    pub fn load(files: &ClientCertFiles) -> Result<Self, String> {
+        Self::from_pem(&read_pem(&files.cert)?, &read_pem(&files.key)?)
+    }
+}
+
+/// The identity a request asks for: `clientCert` naming an entry of
+/// `[tls.client_certs]`, or inline `cert` and `key` PEM.
+pub fn identity(
+    tls: &Tls,
+    name: Option<&str>,
+    cert: Option<&str>,
+    key: Option<&str>,
+) -> Result<Option<ClientIdentity>, String> {
+    match (name, cert, key) {
+        (None, None, None) => Ok(None),
+        (Some(name), None, None) => {
+            let files = tls
+                .client_certs
+                .get(name)
+                .ok_or_else(|| format!("unknown client certificate: {name}"))?;
+            ClientIdentity::load(files).map(Some)
+        }
+        (None, Some(cert), Some(key)) => ClientIdentity::from_pem(cert, key).map(Some),
+        (Some(_), _, _) => Err("clientCert cannot be combined with cert/key".to_string()),
+        _ => Err("cert and key must be given together".to_string()),
+    }
+}
+
+/// Client config for outbound TLS. A request's `ca` PEM replaces the default
+/// roots, like Node's `ca` option; `insecure` lets the handshake go ahead
+/// whatever the server certificate check says, as `rejectUnauthorized: false`
+/// does, and the returned verdict records what it said. `alpn` lists the
+/// protocol ids to offer, most preferred first.
+pub fn client_config(
+    insecure: bool,
+    ca: Option<&str>,
+    identity: Option<&ClientIdentity>,
+    alpn: &[String],
+) -> Result<(ClientConfig, Verdict), String> {
+    if let Some(bad) = alpn.iter().find(|p| p.is_empty() || p.len() > 255) {
+        return Err(format!("invalid ALPN protocol: {bad:?}"));
+    }
+    let roots = match ca {
+        Some(pem) => {
+            let mut roots = RootCertStore::empty();
+            for cert in parse_certs(pem, "ca")? {
+                roots.add(&cert).map_err(|e| format!("invalid ca: {e}"))?;
+            }
+            roots
+        }
+        None => DEFAULT_ROOTS.get().cloned().unwrap_or_else(webpki_roots),
+    };
+
+    let builder = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots.clone());
+    let mut cfg = match identity {
+        Some(identity) => builder
+            .with_client_auth_cert(identity.certs.clone(), identity.key.clone())
+            .map_err(|e| format!("invalid client certificate: {e}"))?,
+        None => builder.with_no_client_auth(),
+    };
+    cfg.alpn_protocols = alpn.iter().map(|p| p.as_bytes().to_vec()).collect();
+    let verdict = Verdict::default();
+    if insecure {
+        cfg.dangerous()
+            .set_certificate_verifier(Arc::new(RecordingVerifier {
+                inner: WebPkiVerifier::new(roots, None),
+                verdict: verdict.clone(),
+            }));
+    }
+    Ok((cfg, verdict))
+}
+
+/// What a finished handshake negotiated, for `TLSSocket.getProtocol()`,
+/// `getCipher()`, `alpnProtocol`, `authorized` and `getPeerCertificate()`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TlsInfo {
+    protocol: Option<String>,
+    cipher: Option<String>,
+    alpn_protocol: Option<String>,
+    authorized: bool,
+    authorization_error: Option<String>,
+    /// The chain as the server sent it, leaf first.
+    peer_certificates: Vec<PeerCertificate>,
+}
+
+/// A certificate in the shape of Node's `getPeerCertificate()`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PeerCertificate {
+    subject: Map<String, Value>,
+    issuer: Map<String, Value>,
+    subjectaltname: Option<String>,
+    valid_from: Option<String>,
+    valid_to: Option<String>,
+    serial_number: Option<String>,
+    fingerprint: String,
+    fingerprint256: String,
+    fingerprint512: String,
+    /// DER, base64-encoded.
+    raw: String,
+}
+
+pub fn session_info(conn: &ClientConnection, verdict: &Verdict) -> TlsInfo {
+    let authorization_error = verdict.lock().unwrap().clone();
+    TlsInfo {
+        protocol: conn.protocol_version().map(|v| match v {
+            ProtocolVersion::TLSv1_3 => "TLSv1.3".to_string(),
+            ProtocolVersion::TLSv1_2 => "TLSv1.2".to_string(),
+            other => format!("{other:?}"),
+        }),
+        cipher: conn
+            .negotiated_cipher_suite()
+            .map(|suite| format!("{:?}", suite.suite()).replacen("TLS13_", "TLS_", 1)),
+        alpn_protocol: conn
+            .alpn_protocol()
+            .map(|p| String::from_utf8_lossy(p).into_owned()),
+        authorized: authorization_error.is_none(),
+        authorization_error,
+        peer_certificates: conn
+            .peer_certificates()
+            .unwrap_or_default()
+            .iter()
+            .map(|cert| peer_certificate(&cert.0))
+            .collect(),
+    }
+}
+
+fn peer_certificate(der: &[u8]) -> PeerCertificate {
+    let mut info = PeerCertificate {
+        subject: Map::new(),
+        issuer: Map::new(),
+        subjectaltname: None,
+        valid_from: None,
+        valid_to: None,
+        serial_number: None,
+        fingerprint: fingerprint(&digest::SHA1_FOR_LEGACY_USE_ONLY, der),
+        fingerprint256: fingerprint(&digest::SHA256, der),
+        fingerprint512: fingerprint(&digest::SHA512, der),
+        raw: general_purpose::STANDARD.encode(der),
+    };
+    let cert = match x509_parser::parse_x509_certificate(der) {
+        Ok((_, cert)) => cert,
+        Err(e) => {
+            log::debug!("Cannot parse peer certificate: {e}");
+            return info;
+        }
+    };
+    info.subject = name_fields(cert.subject());
+    info.issuer = name_fields(cert.issuer());
+    info.valid_from = Some(node_time(&cert.validity().not_before));
+    info.valid_to = Some(node_time(&cert.validity().not_after));
+    info.serial_number = Some(
+        cert.raw_serial()
+            .iter()
+            .map(|b| format!("{b:02X}"))
+            .collect(),
+    );
+    if let Ok(Some(san)) = cert.subject_alternative_name() {
+        let names: Vec<String> = san
+            .value
+            .general_names
+            .iter()
+            .filter_map(|name| match name {
+                GeneralName::DNSName(dns) => Some(format!("DNS:{dns}")),
+                GeneralName::RFC822Name(email) => Some(format!("email:{email}")),
+                GeneralName::URI(uri) => Some(format!("URI:{uri}")),
+                GeneralName::IPAddress(ip) => ip_address(ip).map(|ip| format!("IP Address:{ip}")),
+                _ => None,
+            })
+            .collect();
+        info.subjectaltname = Some(names.join(", "));
+    }
+    info
+}
+
+/// A distinguished name keyed by attribute short name (`CN`, `O`, ...);
+/// repeated attributes become arrays, as in Node.
+fn name_fields(name: &X509Name) -> Map<String, Value> {
+    let mut fields = Map::new();
+    for attr in name.iter_attributes() {
+        let Ok(value) = attr.as_str() else {
+            continue;
+        };
+        let oid = attr.attr_type();
+        let key = match oid2sn(oid, oid_registry()) {
+            Ok("commonName") => "CN".to_string(),
+            Ok("countryName") => "C".to_string(),
+            Ok("localityName") => "L".to_string(),
+            Ok("stateOrProvinceName") => "ST".to_string(),
+            Ok("organizationName") => "O".to_string(),
+            Ok("organizationalUnitName") => "OU".to_string(),
+            Ok("domainComponent") => "DC".to_string(),
+            Ok(sn) => sn.to_string(),
+            Err(_) => oid.to_id_string(),
+        };
+        match fields.get_mut(&key) {
+            Some(Value::Array(values)) => values.push(value.into()),
+            Some(existing) => *existing = Value::Array(vec![existing.take(), value.into()]),
+            None => {
+                fields.insert(key, value.into());
+            }
+        }
+    }
+    fields
+}
+
+/// `Mar  1 12:00:00 2027 GMT`, the format of Node's `valid_from`.
+fn node_time(time: &ASN1Time) -> String {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let t = time.to_datetime();
+    format!(
+        "{} {:>2} {:02}:{:02}:{:02} {} GMT",
+        MONTHS[t.month() as usize - 1],
+        t.day(),
+        t.hour(),
+        t.minute(),
+        t.second(),
+        t.year()
+    )
+}
+
+fn ip_address(bytes: &[u8]) -> Option<std::net::IpAddr> {
+    match bytes.len() {
+        4 => Some(<[u8; 4]>::try_from(bytes).ok()?.into()),
+        16 => Some(<[u8; 16]>::try_from(bytes).ok()?.into()),
+        _ => None,
+    }
+}
+
+/// Colon-separated uppercase hex, as Node prints fingerprints.
+fn fingerprint(algorithm: &'static digest::Algorithm, der: &[u8]) -> String {
+    digest::digest(algorithm, der)
+        .as_ref()
+        .iter()
+        .map(|b| format!("{b:02X}"))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// Server config for `wss://` listeners: the configured certificate, or a
+/// fresh self-signed one, returned with its SHA-256 fingerprint so clients
+/// can check what they are asked to trust.
+pub fn server_config(server: &ServerTls) -> Result<(ServerConfig, Option<String>), String> {
+    let (certs, key, fingerprint) = match (&server.cert, &server.key) {
+        (Some(cert), Some(key)) => (
+            parse_certs(&read_pem(cert)?, "server certificate")?,
+            parse_key(&read_pem(key)?, "server key")?,
+            None,
+        ),
+        _ => {
+            let cert = rcgen::generate_simple_self_signed(server.self_signed_names.clone())
+                .map_err(|e| format!("cannot generate certificate: {e}"))?;
+            let der = cert
+                .serialize_der()
+                .map_err(|e| format!("cannot generate certificate: {e}"))?;
+            let fingerprint = fingerprint(&digest::SHA256, &der);
+            let key = PrivateKey(cert.serialize_private_key_der());
+            (vec![Certificate(der)], key, Some(fingerprint))
+        }
+    };
+    let cfg = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| format!("invalid server certificate: {e}"))?;
+    Ok((cfg, fingerprint))
+}
+
+/// A client's connection to the proxy, over TLS on `wss://` listeners.
+pub enum ServerStream {
+    Plain(TcpStream),
+    Tls(Box<tokio_rustls::server::TlsStream<TcpStream>>),
+}
+
+impl AsyncRead for ServerStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ServerStream::Plain(s) => Pin::new(s).poll_read(cx, buf),
+            ServerStream::Tls(s) => Pin::new(s.as_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for ServerStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            ServerStream::Plain(s) => Pin::new(s).poll_write(cx, buf),
+            ServerStream::Tls(s) => Pin::new(s.as_mut()).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ServerStream::Plain(s) => Pin::new(s).poll_flush(cx),
+            ServerStream::Tls(s) => Pin::new(s.as_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ServerStream::Plain(s) => Pin::new(s).poll_shutdown(cx),
+            ServerStream::Tls(s) => Pin::new(s.as_mut()).poll_shutdown(cx),
+        }
+    }
+}